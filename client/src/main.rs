@@ -1,58 +1,76 @@
-use std::net::UdpSocket;
-use kscope::crypto::keyfile::load_keys;
-use kscope::protocol::handshake::Handshake;
-use kscope::protocol::packet::{Packet, TransportData};
-use kscope::protocol::transport::SecureTransport;
-use kscope::tun::{TunConfig, TunDevice};
-use bytes::Bytes;
+use clap::Parser;
+use kscope::client::KScopeClient;
+use kscope::protocol::{expand_home, ClientConfig};
+use std::path::PathBuf;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
-
-    let keys = load_keys("keys/client.keys");
-
-    let mut tun = TunDevice::create(TunConfig {
-        name: "kscope0".into(),
-        ip: "10.8.0.2".parse()?,
-        prefix_len: 24,
-        mtu: 1400,
-    })?;
-
-    let sock = UdpSocket::bind("0.0.0.0:0")?;
-    let server = "192.168.38.127:7000";
-
-    let mut hs = Handshake::new_initiator(&keys.private, &keys.peer_public, &keys.psk)?;
-    let mut buf = [0u8; 2048];
-
-    let n = hs.next_outbound(&mut buf)?;
-    sock.send_to(&buf[..n], server)?;
-
-    loop {
-        let (n, _) = sock.recv_from(&mut buf)?;
-        hs.process_inbound(&buf[..n])?;
+/// KScope VPN client.
+///
+/// Settings come from the config file; any flag given overrides the
+/// matching setting.
+#[derive(Parser)]
+#[command(name = "kscope-client")]
+struct Cli {
+    /// Client config file.
+    #[arg(short, long, default_value = "~/.config/kscope/client.toml")]
+    config: PathBuf,
+    /// Server address (host:port).
+    #[arg(long)]
+    server: Option<String>,
+    /// Private key file.
+    #[arg(long)]
+    key: Option<PathBuf>,
+    /// Server public key file, or the private key file to use its PEER_PUBLIC.
+    #[arg(long)]
+    server_key: Option<PathBuf>,
+    #[arg(long)]
+    tun_name: Option<String>,
+    /// Tunnel address and prefix, until the server pushes one.
+    #[arg(long)]
+    tun_ip: Option<String>,
+    #[arg(long)]
+    mtu: Option<u16>,
+    /// Log level: trace, debug, info, warn or error.
+    #[arg(long)]
+    log_level: Option<String>,
+    /// Exit when the connection fails instead of reconnecting.
+    #[arg(long)]
+    no_reconnect: bool,
+}
 
-        let out = hs.next_outbound(&mut buf)?;
-        if out > 0 {
-            sock.send_to(&buf[..out], server)?;
-        }
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    let mut config = ClientConfig::load(&expand_home(&cli.config))?;
 
-        if hs.is_complete() {
-            break;
-        }
+    if let Some(server) = cli.server {
+        config.client.server_addr = server;
     }
+    if let Some(key) = cli.key {
+        config.client.private_key = key;
+    }
+    if let Some(server_key) = cli.server_key {
+        config.client.server_public_key = server_key;
+    }
+    if let Some(tun_name) = cli.tun_name {
+        config.network.tun_name = tun_name;
+    }
+    if let Some(tun_ip) = cli.tun_ip {
+        config.network.tun_ip = tun_ip;
+    }
+    if let Some(mtu) = cli.mtu {
+        config.network.mtu = mtu;
+    }
+    if let Some(level) = cli.log_level {
+        config.logging.level = level;
+    }
+    if cli.no_reconnect {
+        config.client.auto_reconnect = false;
+    }
+    config.expand_paths();
+    config.logging.init()?;
 
-    println!("Client: handshake complete");
-
-    let mut transport = SecureTransport::new(hs.into_session());
-
-    loop {
-        let (n, _) = sock.recv_from(&mut buf)?;
-        let (pkt, _) = Packet::deserialize(&buf[..n])?;
+    let mut client = KScopeClient::new(config).await?;
+    client.run().await?;
 
-        if let Packet::TransportData(TransportData { ciphertext, .. }) = pkt {
-            let mut plain = vec![0u8; ciphertext.len()];
-            let len = transport.decrypt(&ciphertext, &mut plain)?;
-            tun.write(&plain[..len])?;
-        }
-    }
+    Ok(())
 }
@@ -1,5 +1,4 @@
 use tokio::net::UdpSocket;
-use std::net::SocketAddr;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -1,45 +1,62 @@
 // examples/noise_handshake_test.rs
-use kscope::crypto::KeyPair;
-use kscope::crypto::noise_handshake::NoiseHandshake;
+use kscope::crypto::keys::KeyPair;
+use kscope::crypto::secret::SecretKey;
+use kscope::protocol::handshake::Handshake;
+use kscope::server::registry::{PeerEntry, PeerRegistry};
+use rand::RngCore;
+use std::sync::Arc;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("=== Noise Handshake Test ===");
     
     // 1. Генерируем ключи для клиента и сервера
     println!("Generating key pairs...");
-    let client_keys = KeyPair::generate()?;
-    let server_keys = KeyPair::generate()?;
-    
-    println!("Client public key: {}", client_keys.public_key_hex());
-    println!("Server public key: {}", server_keys.public_key_hex());
-    
-    // 2. Клиент создает init сообщение
-    println!("\nClient creating init message...");
-    let mut client_handshake = NoiseHandshake::initiator(
-        &client_keys,
-        &server_keys.public_key()
+    let client_keys = KeyPair::generate();
+    let server_keys = KeyPair::generate();
+    let mut psk = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut psk);
+    
+    println!("Client public key: {}", hex::encode(client_keys.public.as_bytes()));
+    println!("Server public key: {}", hex::encode(server_keys.public.as_bytes()));
+    
+    let mut client = Handshake::new_initiator(
+        &client_keys.secret(),
+        server_keys.public.as_bytes(),
+        &SecretKey::new(psk),
+    )?;
+    let mut peers = PeerRegistry::default();
+    peers.insert(PeerEntry {
+        name: "client".into(),
+        public_key: *client_keys.public.as_bytes(),
+        psk: Some(SecretKey::new(psk)),
+        allowed_ips: Vec::new(),
+        enabled: true,
+    })?;
+    let mut server = Handshake::new_responder(
+        &server_keys.secret(),
+        Arc::new(peers),
     )?;
     
-    let init_message = client_handshake.write_init_message()?;
-    println!("Init message: {} bytes", init_message.len());
-    
-    // 3. Сервер обрабатывает init сообщение
-    println!("\nServer processing init message...");
-    let mut server_handshake = NoiseHandshake::responder(&server_keys)?;
-    
-    let response = server_handshake.read_message(&init_message)?;
-    
-    if let Some(response_data) = response {
-        println!("Server response: {} bytes", response_data.len());
+    // 2. Обмениваемся сообщениями, пока обе стороны не закончат
+    let mut buf = [0u8; 1024];
+    let mut round = 1;
+    while !client.is_complete() || !server.is_complete() {
+        let n = client.next_outbound(&mut buf)?;
+        if n > 0 {
+            println!("Client -> Server: message {} ({} bytes)", round, n);
+            server.process_inbound(&buf[..n])?;
+            round += 1;
+        }
         
-        // 4. Клиент завершает handshake
-        println!("\nClient finishing handshake...");
-        // TODO: client_handshake.finish_handshake(&response_data)?;
-        
-        println!("✅ Handshake completed successfully!");
-    } else {
-        println!("❌ No response from server");
+        let n = server.next_outbound(&mut buf)?;
+        if n > 0 {
+            println!("Server -> Client: message {} ({} bytes)", round, n);
+            client.process_inbound(&buf[..n])?;
+            round += 1;
+        }
     }
     
+    println!("✅ Handshake completed successfully!");
+    
     Ok(())
 }
@@ -1,5 +1,4 @@
 use kscope::tun::{TunDevice, TunConfig};
-use std::net::Ipv4Addr;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("=== KScope Simple TUN Test ===");
@@ -15,7 +14,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
              config.name, config.ip);
     
     match TunDevice::create(config) {
-        Ok(mut tun) => {
+        Ok(tun) => {
             println!("✅ TUN interface created successfully!");
             println!("   Interface name: {}", tun.name());
             
@@ -46,7 +45,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         received = true;
                         break;
                     }
-                    Err(e) => {
+                    Err(_) => {
                         // Просто продолжаем ждать
                         std::thread::sleep(std::time::Duration::from_millis(10));
                         continue;
@@ -75,7 +74,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             println!("2. Check tun module: sudo lsmod | grep tun");
             println!("3. Load tun module: sudo modprobe tun");
             println!("4. Check permissions: ls -la /dev/net/tun");
-            return Ok(());
+            Ok(())
         }
     }
 }
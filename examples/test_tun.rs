@@ -1,6 +1,5 @@
 // examples/test_tun.rs
 use kscope::tun::{TunDevice, TunConfig};
-use std::net::Ipv4Addr;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Включаем логирование
@@ -19,7 +18,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Creating TUN interface '{}' with IP {}...", 
              config.name, config.ip);
     
-    let mut tun = TunDevice::create(config)?;
+    let tun = TunDevice::create(config)?;
     
     println!("TUN interface created successfully!");
     println!("Interface name: {}", tun.name());
@@ -85,7 +84,7 @@ fn create_test_ipv4_packet() -> Vec<u8> {
     // Version (4) + IHL (5) = 0x45
     packet.push(0x45);                     // Version + IHL
     packet.push(0x00);                     // DSCP + ECN
-    packet.extend(&(20 + 32).to_be_bytes()[2..]); // Total Length (52 bytes)
+    packet.extend(&(20u32 + 32).to_be_bytes()[2..]); // Total Length (52 bytes)
     packet.extend(&[0x00, 0x00]);          // Identification
     packet.extend(&[0x40, 0x00]);          // Flags + Fragment Offset
     packet.push(0x40);                     // TTL (64)
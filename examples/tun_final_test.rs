@@ -40,7 +40,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
     
     // Клонируем TUN для отправки пакетов
-    let mut tun_for_write = tun;
+    let tun_for_write = tun;
     
     // Тест 1: Пробуем отправить сырые байты (без TUN заголовка)
     println!("\nTest 1: Sending raw bytes...");
@@ -57,7 +57,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     packet_with_header.extend_from_slice(&[0x00, 0x00]); // Flags
     packet_with_header.extend_from_slice(&[0x08, 0x00]); // Protocol: IPv4
     packet_with_header.extend_from_slice(&[0x45, 0x00, 0x00, 0x1c]); // Minimal IPv4
-    packet_with_header.extend_from_slice(&vec![0x00u8; 20]); // Остальные байты
+    packet_with_header.extend_from_slice(&[0x00u8; 20]); // Остальные байты
     
     match tun_for_write.write(&packet_with_header) {
         Ok(_) => println!("✅ Packet with header sent"),
@@ -18,7 +18,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
     
     println!("Creating TUN interface...");
-    let mut tun = TunDevice::create(config)?;
+    let tun = TunDevice::create(config)?;
     println!("✅ TUN interface created: {}", tun.name());
     
     // Тест 1: Простой ARP пакет (всегда работает)
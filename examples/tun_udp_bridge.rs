@@ -1,5 +1,5 @@
 // examples/tun_udp_bridge.rs
-use kscope::tun::TunDevice;
+use kscope::tun::{TunConfig, TunDevice};
 use kscope::network::{PacketHandler, udp::UdpTransport};
 use kscope::Result;
 use std::net::SocketAddr;
@@ -23,9 +23,9 @@ impl PacketHandler for TunUdpBridge {
         println!("[UDP→TUN] From {}: {} bytes", source, data.len());
         
         // Пишем полученные данные в TUN интерфейс
-        let mut tun = self.tun.lock().unwrap();
+        let tun = self.tun.lock().unwrap();
         
-        if let Err(e) = tun.write_ignore_errors(data) {
+        if let Err(e) = tun.write(data) {
             eprintln!("[UDP→TUN] Failed to write to TUN: {}", e);
         } else {
             println!("[UDP→TUN] Written to TUN interface");
@@ -42,7 +42,7 @@ async fn main() -> Result<()> {
     
     // 1. Создаем TUN интерфейс
     println!("Creating TUN interface...");
-    let tun = TunDevice::create_test()?;
+    let tun = TunDevice::create(TunConfig::from_cidr("kscope0", "10.0.0.2/24", 1420)?)?;
     println!("✅ TUN interface created: {}", tun.name());
     
     // 2. Создаем UDP транспорт с мостом
@@ -1,6 +1,5 @@
 // examples/tun_verified.rs
-use kscope::tun::TunDevice;
-use hex;
+use kscope::tun::{TunConfig, TunDevice};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("=== Verified TUN Test ===");
@@ -12,19 +11,21 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     
     // Создаем
     println!("Creating TUN interface...");
-    let mut tun = TunDevice::create_test()?;
+    let config = TunConfig::from_cidr("kscope0", "10.0.0.2/24", 1420)?;
+    let ip = config.ip;
+    let tun = TunDevice::create(config)?;
     
     println!("✅ TUN interface created!");
     println!("   Name: {}", tun.name());
-    println!("   IP: {}", tun.ip());
+    println!("   IP: {}", ip);
     println!("   MTU: {}", tun.mtu());
     
     // Отправляем тестовый пакет (игнорируем ошибку)
     println!("\nSending test packet (will ignore tun_tap errors)...");
     let test_packet = hex::decode("4500003c00004000040100000a000002080808080800000000000100016162636465666768696a6b6c6d6e6f7071727374757677616263646566676869").unwrap();
     
-    // Используем метод, который игнорирует ошибки
-    tun.write_ignore_errors(&test_packet)?;
+    // Игнорируем ошибку записи
+    let _ = tun.write(&test_packet);
     println!("✅ Packet sent (errors ignored due to tun_tap bug)");
     
     // Проверяем в системе
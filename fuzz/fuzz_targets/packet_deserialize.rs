@@ -0,0 +1,16 @@
+#![no_main]
+
+use kscope::protocol::packet::{Packet, PacketHeader};
+use libfuzzer_sys::fuzz_target;
+
+// Any datagram off the wire must decode or fail cleanly, never panic.
+fuzz_target!(|data: &[u8]| {
+    let header = PacketHeader::deserialize(data);
+    let packet = Packet::deserialize(data);
+
+    if let Ok((packet, session_id)) = packet {
+        let header = header.expect("packet decoded without a valid header");
+        assert_eq!(session_id, header.session_id);
+        assert_eq!(packet.packet_type(), header.packet_type);
+    }
+});
@@ -1,64 +1,73 @@
-use std::net::{SocketAddr, UdpSocket};
-use bytes::Bytes;
-use kscope::crypto::keyfile::load_keys;
-use kscope::protocol::handshake::Handshake;
-use kscope::protocol::packet::{Packet, TransportData};
-use kscope::protocol::transport::SecureTransport;
-use kscope::tun::{TunConfig, TunDevice};
+use clap::Parser;
+use kscope::protocol::{expand_home, ServerConfig};
+use kscope::server::KScopeServer;
+use std::path::PathBuf;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
-
-    let keys = load_keys("keys/server.keys");
-
-    let mut tun = TunDevice::create(TunConfig {
-        name: "kscope0".into(),
-        ip: "10.8.0.1".parse()?,
-        prefix_len: 24,
-        mtu: 1400,
-    })?;
-
-    let sock = UdpSocket::bind("0.0.0.0:7000")?;
-    let mut hs = Handshake::new_responder(&keys.private, &keys.peer_public, &keys.psk)?;
-    let mut buf = [0u8; 2048];
-
-    let mut peer: Option<SocketAddr> = None;
-
-    // 🧷 правильный XX handshake
-    loop {
-        let (n, addr) = sock.recv_from(&mut buf)?;
-        peer = Some(addr);
-
-        hs.process_inbound(&buf[..n])?;
+/// KScope VPN server.
+///
+/// Settings come from the config file; any flag given overrides the
+/// matching setting.
+#[derive(Parser)]
+#[command(name = "kscope-server")]
+struct Cli {
+    /// Server config file.
+    #[arg(short, long, default_value = "/etc/kscope/server.toml")]
+    config: PathBuf,
+    /// Address to listen on (ip:port).
+    #[arg(long)]
+    listen: Option<String>,
+    /// Private key file.
+    #[arg(long)]
+    key: Option<PathBuf>,
+    /// Peers file or directory.
+    #[arg(long)]
+    peers: Option<PathBuf>,
+    #[arg(long)]
+    tun_name: Option<String>,
+    /// Server tunnel address; its prefix is the client network.
+    #[arg(long)]
+    tun_ip: Option<String>,
+    #[arg(long)]
+    mtu: Option<u16>,
+    /// Log level: trace, debug, info, warn or error.
+    #[arg(long)]
+    log_level: Option<String>,
+}
 
-        if !hs.is_complete() {
-            let out = hs.next_outbound(&mut buf)?;
-            if out > 0 {
-                sock.send_to(&buf[..out], peer.unwrap())?;
-            }
-        }
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    let mut config = ServerConfig::load(&expand_home(&cli.config))?;
 
-        if hs.is_complete() {
-            break;
-        }
+    if let Some(listen) = cli.listen {
+        config.server.listen_addr = listen;
     }
+    if let Some(key) = cli.key {
+        config.server.private_key = key;
+    }
+    if let Some(peers) = cli.peers {
+        config.server.peers = Some(peers);
+    }
+    if let Some(tun_name) = cli.tun_name {
+        config.network.tun_name = tun_name;
+    }
+    if let Some(tun_ip) = cli.tun_ip {
+        config.network.tun_ip = tun_ip;
+    }
+    if let Some(mtu) = cli.mtu {
+        config.network.mtu = mtu;
+    }
+    if let Some(level) = cli.log_level {
+        config.logging.level = level;
+    }
+    config.expand_paths();
+    config.logging.init()?;
 
-    println!("Server: handshake complete");
-
-    let mut transport = SecureTransport::new(hs.into_session());
-
-    loop {
-        let packet = tun.read()?;
-
-        let mut encrypted = vec![0u8; packet.len() + 64];
-        let len = transport.encrypt(&packet, &mut encrypted)?;
-
-        let pkt = Packet::TransportData(TransportData {
-            nonce: 0,
-            ciphertext: Bytes::copy_from_slice(&encrypted[..len]),
-        });
+    let mut server = KScopeServer::new(config).await?;
+    let stats = server.stats();
+    println!("Server: waiting for clients");
+    server.run().await?;
+    println!("Server: stopped ({})", stats);
 
-        let out = pkt.serialize(0);
-        sock.send_to(&out, peer.unwrap())?;
-    }
+    Ok(())
 }
@@ -1,21 +1,359 @@
-use kscope::crypto::keys::KeyPair;
 use base64::{engine::general_purpose, Engine};
+use clap::{Parser, Subcommand};
+use kscope::crypto::keyfile::{load_keys, read_new_passphrase};
+use kscope::crypto::keys::{fingerprint, KeyPair};
+use kscope::crypto::sealed;
+use kscope::net::cidr::IpCidr;
+use kscope::server::registry::PeerRegistry;
 use rand::RngCore;
+use serde::Serialize;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// KScope key management.
+///
+/// Every file is created with mode 0600 and existing files are left alone
+/// unless --force is given.
+#[derive(Parser)]
+#[command(name = "genkeys")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Generate a keypair: OUT gets the private key file, OUT.pub the public key.
+    Keypair {
+        out: PathBuf,
+        /// Base64 public key of the peer (the server, for a client) to add as PEER_PUBLIC.
+        #[arg(long)]
+        peer: Option<String>,
+        /// Protect the private key file with a passphrase.
+        #[arg(long)]
+        encrypt: bool,
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print (or write) the public key of a private key file.
+    Pubkey {
+        key_file: PathBuf,
+        #[arg(long)]
+        out: Option<PathBuf>,
+        #[arg(long)]
+        force: bool,
+    },
+    /// Generate a random base64 pre-shared key.
+    Psk {
+        out: PathBuf,
+        #[arg(long)]
+        force: bool,
+    },
+    /// Generate client key files and append them as [[peer]] entries to a server registry.
+    AddPeers {
+        /// The server's peers file; created if missing.
+        #[arg(long)]
+        registry: PathBuf,
+        /// The server's key file, for the clients' PEER_PUBLIC.
+        #[arg(long)]
+        server_keys: PathBuf,
+        /// Directory for the new client key files (<name>.keys).
+        #[arg(long)]
+        out_dir: PathBuf,
+        #[arg(long, default_value_t = 1)]
+        count: usize,
+        /// Clients are named <prefix><n>, skipping names already registered.
+        #[arg(long, default_value = "client")]
+        prefix: String,
+        /// Give each client the next free /32 from this IPv4 network
+        /// (the first host is left for the server).
+        #[arg(long)]
+        subnet: Option<IpCidr>,
+        /// Give each client its own PSK.
+        #[arg(long)]
+        psk: bool,
+        /// Protect the client key files with a passphrase.
+        #[arg(long)]
+        encrypt: bool,
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print fingerprints of the keys in key files, .pub files or peer registries.
+    Fingerprint {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+}
+
+#[derive(Serialize)]
+struct PeerRecord {
+    name: String,
+    public_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    psk: Option<String>,
+    allowed_ips: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct PeersFile {
+    peer: Vec<PeerRecord>,
+}
 
 fn main() {
-    let server = KeyPair::generate();
-    let client = KeyPair::generate();
+    env_logger::init();
+
+    if let Err(e) = run(Cli::parse().command) {
+        eprintln!("genkeys: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(command: Command) -> Result<()> {
+    let b64 = &general_purpose::STANDARD;
+
+    match command {
+        Command::Keypair { out, peer, encrypt, force } => {
+            let pub_path = with_suffix(&out, ".pub");
+            check_writable(&[&out, &pub_path], force)?;
+
+            let peer = peer.map(|p| decode_public(&p)).transpose()?;
+            let passphrase = if encrypt { Some(read_new_passphrase()?) } else { None };
+
+            let pair = KeyPair::generate();
+            let mut text = Zeroizing::new(format!("PRIVATE={}\n", b64.encode(pair.secret().as_bytes())));
+            if let Some(peer) = peer {
+                text.push_str(&format!("PEER_PUBLIC={}\n", b64.encode(peer)));
+            }
+
+            write_key_file(&out, &text, passphrase.as_deref().map(String::as_str), force)?;
+            write_file(&pub_path, &format!("{}\n", b64.encode(pair.public.as_bytes())), force)?;
+            println!("{}  {}", fingerprint(pair.public.as_bytes()), out.display());
+        }
+        Command::Pubkey { key_file, out, force } => {
+            let keys = load_keys(&key_file)?;
+            let public = b64.encode(KeyPair::from_secret(&keys.private).public.as_bytes());
+            match out {
+                Some(out) => write_file(&out, &format!("{}\n", public), force)?,
+                None => println!("{}", public),
+            }
+        }
+        Command::Psk { out, force } => {
+            let psk = random_key();
+            write_file(&out, &Zeroizing::new(format!("{}\n", b64.encode(psk.as_ref()))), force)?;
+        }
+        Command::AddPeers { registry, server_keys, out_dir, count, prefix, subnet, psk, encrypt, force } => {
+            add_peers(&registry, &server_keys, &out_dir, count, &prefix, subnet, psk, encrypt, force)?;
+        }
+        Command::Fingerprint { paths } => {
+            for path in paths {
+                print_fingerprints(&path)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn add_peers(
+    registry_path: &Path,
+    server_keys: &Path,
+    out_dir: &Path,
+    count: usize,
+    prefix: &str,
+    subnet: Option<IpCidr>,
+    with_psk: bool,
+    encrypt: bool,
+    force: bool,
+) -> Result<()> {
+    let b64 = &general_purpose::STANDARD;
+
+    let existing = match registry_path.exists() {
+        true => Zeroizing::new(fs::read_to_string(registry_path)?),
+        false => Zeroizing::new(String::new()),
+    };
+    let registry = PeerRegistry::from_toml(&existing, registry_path)?;
+    let server_public = *KeyPair::from_secret(&load_keys(server_keys)?.private).public.as_bytes();
+
+    let names: Vec<String> = (1..)
+        .map(|n| format!("{}{}", prefix, n))
+        .filter(|name| registry.iter().all(|p| &p.name != name))
+        .take(count)
+        .collect();
+    let addresses = match subnet {
+        Some(subnet) => free_hosts(&subnet, &registry, count)?.into_iter().map(Some).collect(),
+        None => vec![None; count],
+    };
+
+    let paths: Vec<PathBuf> = names.iter().map(|n| out_dir.join(format!("{}.keys", n))).collect();
+    check_writable(&paths.iter().map(PathBuf::as_path).collect::<Vec<_>>(), force)?;
+    let passphrase = if encrypt { Some(read_new_passphrase()?) } else { None };
+
+    let peers: Vec<_> = names.iter().map(|_| (KeyPair::generate(), with_psk.then(random_key))).collect();
+    let records = names.iter().zip(&peers).zip(addresses)
+        .map(|((name, (pair, psk)), address)| PeerRecord {
+            name: name.clone(),
+            public_key: b64.encode(pair.public.as_bytes()),
+            psk: psk.as_ref().map(|p| b64.encode(p.as_ref())),
+            allowed_ips: address.map(|a| IpCidr::host(a).to_string()).into_iter().collect(),
+        })
+        .collect();
 
-    let mut psk = [0u8; 32];
-    rand::thread_rng().fill_bytes(&mut psk);
+    // Append to the existing text so its comments and layout survive, and
+    // check the result before writing anything.
+    let entries = Zeroizing::new(toml::to_string(&PeersFile { peer: records })?);
+    let text = Zeroizing::new(format!("{}\n{}", existing.as_str(), entries.as_str()));
+    PeerRegistry::from_toml(&text, registry_path)?.allowed_ips()?;
 
-    println!("--- server.keys ---");
-    println!("PRIVATE={}", general_purpose::STANDARD.encode(server.private.to_bytes()));
-    println!("PEER_PUBLIC={}", general_purpose::STANDARD.encode(client.public.as_bytes()));
-    println!("PSK={}", general_purpose::STANDARD.encode(psk));
+    let tmp = with_suffix(registry_path, ".tmp");
+    write_file(&tmp, &text, true)?;
+    let written = write_peer_keys(&names, &paths, &peers, &server_public, passphrase.as_deref().map(String::as_str), out_dir, force);
+    if let Err(e) = written.and_then(|()| Ok(fs::rename(&tmp, registry_path)?)) {
+        fs::remove_file(&tmp).ok();
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Writes one key file per new peer, holding its private key, the
+/// server's public key and its PSK.
+fn write_peer_keys(
+    names: &[String],
+    paths: &[PathBuf],
+    peers: &[(KeyPair, Option<Zeroizing<[u8; 32]>>)],
+    server_public: &[u8; 32],
+    passphrase: Option<&str>,
+    out_dir: &Path,
+    force: bool,
+) -> Result<()> {
+    let b64 = &general_purpose::STANDARD;
+    fs::create_dir_all(out_dir)?;
+
+    for ((name, path), (pair, psk)) in names.iter().zip(paths).zip(peers) {
+        let mut text = Zeroizing::new(format!(
+            "PRIVATE={}\nPEER_PUBLIC={}\n",
+            b64.encode(pair.secret().as_bytes()),
+            b64.encode(server_public)
+        ));
+        if let Some(psk) = psk {
+            text.push_str(&format!("PSK={}\n", b64.encode(psk.as_ref())));
+        }
+        write_key_file(path, &text, passphrase, force)?;
+        println!("{}  {}  {}", fingerprint(pair.public.as_bytes()), name, path.display());
+    }
+    Ok(())
+}
+
+/// The first `count` IPv4 hosts in `subnet` after the server's address
+/// that no registered peer already covers.
+fn free_hosts(subnet: &IpCidr, registry: &PeerRegistry, count: usize) -> Result<Vec<IpAddr>> {
+    let IpAddr::V4(net) = subnet.addr() else {
+        return Err("--subnet must be an IPv4 network".into());
+    };
+    let mask = u32::MAX.checked_shl(32 - subnet.prefix() as u32).unwrap_or(0);
+    let first = u32::from(net) & mask;
+    let last = first | !mask;
+
+    let taken = |ip: &IpAddr| registry.iter().any(|p| p.allowed_ips.iter().any(|c| c.contains(ip)));
+    let hosts: Vec<IpAddr> = (first.saturating_add(2)..last)
+        .map(|n| IpAddr::V4(Ipv4Addr::from(n)))
+        .filter(|ip| !taken(ip))
+        .take(count)
+        .collect();
+
+    if hosts.len() < count {
+        return Err(format!("{} has only {} free address(es)", subnet, hosts.len()).into());
+    }
+    Ok(hosts)
+}
+
+fn print_fingerprints(path: &Path) -> Result<()> {
+    let b64 = &general_purpose::STANDARD;
+
+    if path.is_dir() || path.extension().is_some_and(|e| e == "toml") {
+        for peer in PeerRegistry::load(path)?.iter() {
+            println!("{}  {}  {}", fingerprint(&peer.public_key), peer.name, b64.encode(peer.public_key));
+        }
+    } else if path.extension().is_some_and(|e| e == "pub") {
+        let public = decode_public(fs::read_to_string(path)?.trim())?;
+        println!("{}  {}", fingerprint(&public), path.display());
+    } else {
+        let keys = load_keys(path)?;
+        let public = *KeyPair::from_secret(&keys.private).public.as_bytes();
+        println!("{}  {} (PRIVATE)", fingerprint(&public), path.display());
+        for peer in &keys.peers {
+            println!("{}  {} (PEER_PUBLIC)", fingerprint(peer), path.display());
+        }
+    }
+    Ok(())
+}
+
+fn decode_public(text: &str) -> Result<[u8; 32]> {
+    let bytes = general_purpose::STANDARD.decode(text)?;
+    <[u8; 32]>::try_from(bytes.as_slice()).map_err(|_| format!("public key must be 32 bytes, got {}", bytes.len()).into())
+}
+
+fn random_key() -> Zeroizing<[u8; 32]> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    rand::thread_rng().fill_bytes(key.as_mut());
+    key
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Fails before anything is written if one of `paths` exists and `force`
+/// is not set, so a run never leaves half its files behind.
+fn check_writable(paths: &[&Path], force: bool) -> Result<()> {
+    if force {
+        return Ok(());
+    }
+    match paths.iter().find(|p| p.exists()) {
+        Some(path) => Err(format!("{} already exists (use --force to overwrite)", path.display()).into()),
+        None => Ok(()),
+    }
+}
+
+fn write_key_file(path: &Path, text: &str, passphrase: Option<&str>, force: bool) -> Result<()> {
+    match passphrase {
+        Some(passphrase) => write_file(path, &sealed::seal(text.as_bytes(), passphrase.as_bytes())?, force),
+        None => write_file(path, text, force),
+    }
+}
+
+fn write_file(path: &Path, contents: &str, force: bool) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    let mut file = open_private(path, &mut options)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
 
-    println!("\n--- client.keys ---");
-    println!("PRIVATE={}", general_purpose::STANDARD.encode(client.private.to_bytes()));
-    println!("PEER_PUBLIC={}", general_purpose::STANDARD.encode(server.public.as_bytes()));
-    println!("PSK={}", general_purpose::STANDARD.encode(psk));
+/// Opens with mode 0600, also tightening the mode of a file that already existed.
+fn open_private(path: &Path, options: &mut OpenOptions) -> std::io::Result<fs::File> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        let file = options.open(path)?;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        Ok(file)
+    }
+    #[cfg(not(unix))]
+    options.open(path)
 }
@@ -0,0 +1,133 @@
+use crate::net::cidr::IpCidr;
+use crate::protocol::control::TunnelConfig;
+use crate::protocol::NetworkSettings;
+use crate::tun::{add_route, delete_route, route_to, TunDevice};
+use crate::{KScopeError, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+/// Applies server-pushed `TunnelConfig` to the client's TUN device.
+///
+/// DNS servers from the client's own config replace pushed ones, and its
+/// own routes are added to the pushed ones. Before a route covering the
+/// server's endpoint (such as `0.0.0.0/0`) is installed, the server's
+/// address is pinned to the path it has now, so the tunnel's own packets
+/// do not loop into the tunnel. The pin is removed when the applier is
+/// dropped. A default route is installed as its two halves, which win
+/// over the existing default route without replacing it.
+pub struct ConfigApplier {
+    tun: Arc<TunDevice>,
+    server: SocketAddr,
+    /// The address from the local config, until the server pushes one.
+    address: IpAddr,
+    dns: Vec<IpAddr>,
+    routes: Vec<IpCidr>,
+    applied: Mutex<Option<TunnelConfig>>,
+    /// Host route to the server outside the tunnel, once installed.
+    pinned: Mutex<Option<String>>,
+}
+
+impl ConfigApplier {
+    pub fn new(tun: Arc<TunDevice>, server: SocketAddr, network: &NetworkSettings) -> Result<Self> {
+        let dns = network.dns_servers.iter()
+            .map(|s| s.parse().map_err(|_| KScopeError::Config(format!("invalid DNS server: {}", s))))
+            .collect::<Result<_>>()?;
+        let routes = network.routes.iter().map(|r| r.parse()).collect::<Result<_>>()?;
+        let address = network.tun_ip.parse::<IpCidr>()?.addr();
+        Ok(Self { tun, server, address, dns, routes, applied: Mutex::new(None), pinned: Mutex::new(None) })
+    }
+
+    pub fn is_applied(&self) -> bool {
+        self.applied.lock().unwrap().is_some()
+    }
+
+    /// The tunnel address in use: the pushed one, else the local one.
+    pub fn address(&self) -> IpAddr {
+        let applied = self.applied.lock().unwrap();
+        applied.as_ref().and_then(|c| c.address.as_ref()).map_or(self.address, IpCidr::addr)
+    }
+
+    /// Configures the TUN device from `config`. A repeat of the config
+    /// already applied is ignored, so a late duplicate does not flap the
+    /// interface.
+    pub fn apply(&self, config: &TunnelConfig) -> Result<()> {
+        let mut applied = self.applied.lock().unwrap();
+        if applied.as_ref() == Some(config) {
+            return Ok(());
+        }
+
+        if let Some(address) = &config.address {
+            self.tun.set_address(address)?;
+            log::info!("Tunnel address {}", address);
+        }
+        if config.mtu > 0 && config.mtu as usize != self.tun.mtu() {
+            self.tun.set_mtu(config.mtu)?;
+            log::info!("Tunnel MTU {}", config.mtu);
+        }
+
+        let dns = if self.dns.is_empty() { &config.dns } else { &self.dns };
+        if !dns.is_empty() {
+            match self.tun.set_dns(dns) {
+                Ok(()) => log::info!("DNS servers {:?}", dns),
+                Err(e) => log::warn!("Could not set DNS servers: {}", e),
+            }
+        }
+
+        for route in config.routes.iter().chain(&self.routes).flat_map(split_default) {
+            if route.contains(&self.server.ip()) {
+                if let Err(e) = self.pin_server() {
+                    log::warn!("Not routing {} into the tunnel: it contains the server {}: {}", route, self.server.ip(), e);
+                    continue;
+                }
+            }
+            match add_route(self.tun.name(), &route.to_string(), None) {
+                Ok(()) => log::info!("Route {} via {}", route, self.tun.name()),
+                Err(e) => log::warn!("Could not add route {}: {}", route, e),
+            }
+        }
+
+        *applied = Some(config.clone());
+        Ok(())
+    }
+
+    /// Routes the server's address the way it is routed now, ahead of
+    /// any tunnel route that covers it.
+    fn pin_server(&self) -> Result<()> {
+        let mut pinned = self.pinned.lock().unwrap();
+        if pinned.is_some() {
+            return Ok(());
+        }
+        let (via, dev) = route_to(self.server.ip())?;
+        if dev == self.tun.name() {
+            return Err(KScopeError::Config("it is already routed into the tunnel".into()));
+        }
+        let host = IpCidr::host(self.server.ip()).to_string();
+        add_route(&dev, &host, via.as_deref())?;
+        log::info!("Route {} via {}", host, via.as_deref().unwrap_or(&dev));
+        *pinned = Some(host);
+        Ok(())
+    }
+}
+
+impl Drop for ConfigApplier {
+    fn drop(&mut self) {
+        if let Some(host) = self.pinned.get_mut().unwrap().take() {
+            if let Err(e) = delete_route(&host) {
+                log::warn!("Could not remove route {}: {}", host, e);
+            }
+        }
+    }
+}
+
+/// `route` itself, or for a default route its two halves (`0.0.0.0/1` and
+/// `128.0.0.0/1`, or `::/1` and `8000::/1`).
+fn split_default(route: &IpCidr) -> Vec<IpCidr> {
+    if route.prefix() > 0 {
+        return vec![*route];
+    }
+    let (low, high) = match route.addr() {
+        IpAddr::V4(_) => (IpAddr::V4(Ipv4Addr::UNSPECIFIED), IpAddr::V4(Ipv4Addr::new(128, 0, 0, 0))),
+        IpAddr::V6(_) => (IpAddr::V6(Ipv6Addr::UNSPECIFIED), IpAddr::V6(Ipv6Addr::new(0x8000, 0, 0, 0, 0, 0, 0, 0))),
+    };
+    [low, high].into_iter().filter_map(|addr| IpCidr::new(addr, 1).ok()).collect()
+}
@@ -0,0 +1,478 @@
+mod apply;
+
+use crate::crypto::keyfile::{load_keys, load_public_key, LoadedKeys};
+use crate::crypto::noise::NO_PSK;
+use crate::crypto::secret::SecretKey;
+use crate::net::routing::RoutingTable;
+use crate::protocol::capabilities::Capabilities;
+use crate::protocol::control::{Control, TunnelConfig};
+use crate::protocol::handshake::{Handshake, HandshakeTimers};
+use crate::protocol::packet::{ErrorCode, ErrorPacket, Packet, PacketHeader, PacketType, TransportData, NOTICE_GRACE};
+use crate::protocol::transport::{SecureTransport, SessionInfo, SessionView};
+use crate::protocol::ClientConfig;
+use crate::tun::{self, TunConfig, TunDevice};
+use crate::{KScopeError, Result};
+use apply::ConfigApplier;
+use rand::Rng;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::mpsc;
+
+const MAX_DATAGRAM: usize = 65535;
+
+/// Delay before the first reconnect attempt, doubled for each one after.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Refusals in a row after which the client gives up. Refusals are not
+/// authenticated, so one alone may be forged.
+const MAX_REFUSALS: u32 = 5;
+
+/// TUN packets waiting to be sent, handed from one session to the next.
+type TunQueue = Arc<tokio::sync::Mutex<mpsc::Receiver<Vec<u8>>>>;
+
+/// How many times to ask for the server's config if it does not arrive.
+const CONFIG_REQUESTS: u32 = 5;
+
+/// Receiver indices agreed in the handshake: the server addresses packets
+/// to us with `local`, and we address ours to it with `remote`.
+#[derive(Debug, Clone, Copy)]
+struct SessionIds {
+    local: u32,
+    remote: u32,
+}
+
+/// What outlives a session: the TUN device and how to use it.
+#[derive(Clone)]
+struct Tunnel {
+    tun: Arc<TunDevice>,
+    tun_rx: TunQueue,
+    applier: Arc<ConfigApplier>,
+    allowed_ips: RoutingTable<()>,
+    view: SessionView,
+}
+
+pub struct KScopeClient {
+    config: ClientConfig,
+    keys: LoadedKeys,
+    view: SessionView,
+}
+
+impl KScopeClient {
+    /// Loads our keys. The server's key comes from `server_public_key`, or
+    /// from `PEER_PUBLIC` when that names the private key file itself.
+    pub async fn new(config: ClientConfig) -> Result<Self> {
+        let mut keys = load_keys(&config.client.private_key)?;
+        if config.client.server_public_key != config.client.private_key {
+            keys.peer_public = Some(load_public_key(&config.client.server_public_key)?);
+        }
+        Ok(Self { config, keys, view: SessionView::default() })
+    }
+
+    /// The session with the server, if any, refreshed by `run` once a
+    /// second.
+    pub fn sessions(&self) -> SessionView {
+        self.view.clone()
+    }
+
+    /// Keeps a session with the server until Ctrl-C. With `auto_reconnect`,
+    /// a failed session or handshake is retried after an exponential
+    /// backoff, unless our own config or keys are at fault. A server that
+    /// refuses our key or protocol version is retried at the longest delay,
+    /// up to `MAX_REFUSALS` times in a row. The TUN device and its address
+    /// stay up in between.
+    pub async fn run(&mut self) -> Result<()> {
+        let net = &self.config.network;
+        let tun = Arc::new(TunDevice::create(TunConfig::from_cidr(&net.tun_name, &net.tun_ip, net.mtu)?)?);
+
+        let server_addr = &self.config.client.server_addr;
+        let server = lookup_host(server_addr).await?.next()
+            .ok_or_else(|| KScopeError::Config(format!("{} did not resolve", server_addr)))?;
+        let tunnel = Tunnel {
+            applier: Arc::new(ConfigApplier::new(tun.clone(), server, net)?),
+            allowed_ips: net.allowed_ips()?,
+            tun_rx: Arc::new(tokio::sync::Mutex::new(tun::spawn_reader(tun.clone(), self.config.advanced.buffer_size))),
+            tun,
+            view: self.view.clone(),
+        };
+
+        let client = &self.config.client;
+        let (mut failures, mut refusals) = (0, 0);
+        loop {
+            let result = tokio::select! {
+                res = self.connect(server) => match res {
+                    Ok((socket, transport, ids)) => {
+                        failures = 0;
+                        serve(socket, transport, ids, self.keys.peer_public, tunnel.clone()).await
+                    }
+                    Err(e) => Err(e),
+                },
+                _ = tokio::signal::ctrl_c() => return Ok(()),
+            };
+            let Err(e) = result else { return Ok(()) };
+
+            if !client.auto_reconnect || is_fatal(&e) {
+                return Err(e);
+            }
+            let refused = is_refusal(&e);
+            refusals = if refused { refusals + 1 } else { 0 };
+            if refusals >= MAX_REFUSALS {
+                log::error!("Giving up after {} refusals in a row", refusals);
+                return Err(e);
+            }
+            failures += 1;
+            if client.max_reconnect_attempts > 0 && failures > client.max_reconnect_attempts {
+                log::error!("Giving up after {} reconnect attempts", client.max_reconnect_attempts);
+                return Err(e);
+            }
+            let attempt = if refused { u64::MAX } else { failures };
+            let delay = backoff(attempt, Duration::from_secs(client.reconnect_delay));
+            log::warn!("{}; reconnecting in {:.1?} (attempt {})", e, delay, failures);
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = tokio::signal::ctrl_c() => return Ok(()),
+            }
+        }
+    }
+
+    /// Opens a fresh socket to `server` and handshakes over it.
+    async fn connect(&self, server: SocketAddr) -> Result<(Arc<UdpSocket>, SecureTransport, SessionIds)> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(server).await?;
+        let (transport, ids) = self.handshake(&socket).await?;
+        log::info!("Handshake complete with {} (session {:08x})", server, ids.local);
+        Ok((Arc::new(socket), transport, ids))
+    }
+
+    async fn handshake(&self, socket: &UdpSocket) -> Result<(SecureTransport, SessionIds)> {
+        let server_key = self.keys.peer_public.as_ref()
+            .ok_or_else(|| KScopeError::Config("key file has no PEER_PUBLIC (server key)".into()))?;
+        let psk = self.keys.psk.clone().unwrap_or(SecretKey::new(NO_PSK));
+        let timers = HandshakeTimers::with_timeout(Duration::from_secs(self.config.client.connection_timeout));
+        let mut hs = Handshake::new_initiator(&self.keys.private, server_key, &psk)?
+            .with_timers(timers)
+            .with_capabilities(Capabilities::with_mtu(self.config.network.mtu));
+        let mut buf = [0u8; 2048];
+
+        let n = hs.next_outbound(&mut buf)?;
+        send(socket, &buf[..n]).await?;
+
+        while !hs.is_complete() {
+            let deadline = hs.next_deadline().unwrap_or_else(Instant::now);
+            tokio::select! {
+                res = socket.recv(&mut buf) => match res {
+                    Ok(n) => {
+                        // The server refused us, or someone posing as it did.
+                        if let Ok((Packet::Error(notice), id)) = Packet::deserialize(&buf[..n]) {
+                            if id == hs.local_id() {
+                                return Err(notice.into());
+                            }
+                        }
+                        if let Err(e) = hs.process_inbound(&buf[..n]) {
+                            log::debug!("Ignoring handshake message: {}", e);
+                        }
+                    }
+                    Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                        log::debug!("Server unreachable: {}", e);
+                    }
+                    Err(e) => return Err(e.into()),
+                },
+                _ = tokio::time::sleep_until(deadline.into()) => {}
+            }
+
+            let n = hs.poll(Instant::now(), &mut buf)?;
+            if n > 0 {
+                send(socket, &buf[..n]).await?;
+            }
+        }
+
+        let ids = SessionIds {
+            local: hs.local_id(),
+            remote: hs.remote_id().ok_or_else(|| KScopeError::Protocol("server sent no session ID".into()))?,
+        };
+        // Retrying cannot fix a server that speaks none of our versions.
+        let negotiated = hs.negotiated().map_err(|e| KScopeError::Config(format!("incompatible server: {}", e)))?;
+        log::debug!("Negotiated {:?}", negotiated);
+        let transport = SecureTransport::with_policy(hs.into_session(), self.config.advanced.rekey_policy())?
+            .with_keepalive(self.config.client.keepalive_policy())
+            .with_capabilities(negotiated);
+        Ok((transport, ids))
+    }
+}
+
+/// Runs the TUN→UDP and UDP→TUN pipelines of one session concurrently
+/// until either fails, which ends the session, or Ctrl-C is pressed.
+async fn serve(
+    socket: Arc<UdpSocket>,
+    transport: SecureTransport,
+    ids: SessionIds,
+    server_key: Option<[u8; 32]>,
+    tunnel: Tunnel,
+) -> Result<()> {
+    let server = socket.peer_addr()?;
+    let report = {
+        let (view, applier) = (tunnel.view.clone(), tunnel.applier.clone());
+        move |transport: &SecureTransport| {
+            view.update(vec![SessionInfo {
+                id: ids.local,
+                peer: server_key,
+                endpoint: server,
+                tunnel_ip: Some(applier.address()),
+                rtt: transport.rtt(),
+                stats: transport.stats(),
+            }]);
+        }
+    };
+    report(&transport);
+
+    let transport = Arc::new(Mutex::new(transport));
+    let mut outbound = tokio::spawn(tun_to_udp(tunnel.tun_rx, socket.clone(), transport.clone(), ids.remote, tunnel.allowed_ips.clone()));
+    let mut inbound = tokio::spawn(udp_to_tun(socket.clone(), tunnel.tun, transport.clone(), ids, tunnel.applier.clone(), tunnel.allowed_ips));
+    let mut keepalive = tokio::spawn(keepalive(socket.clone(), transport.clone(), ids.remote, report));
+    let config = tokio::spawn(request_config(socket.clone(), transport, ids.remote, tunnel.applier));
+
+    let result = tokio::select! {
+        res = &mut outbound => res,
+        res = &mut inbound => res,
+        res = &mut keepalive => res,
+        _ = tokio::signal::ctrl_c() => {
+            let notice = Packet::Error(ErrorPacket::new(ErrorCode::ShuttingDown, ""));
+            if let Err(e) = socket.send(&notice.serialize(ids.remote)).await {
+                log::debug!("Could not notify server: {}", e);
+            }
+            Ok(Ok(()))
+        }
+    };
+    outbound.abort();
+    inbound.abort();
+    keepalive.abort();
+    config.abort();
+    tunnel.view.update(Vec::new());
+
+    result.map_err(|e| KScopeError::Io(e.to_string()))?
+}
+
+/// Errors another attempt cannot fix: our own configuration or keys are
+/// wrong.
+fn is_fatal(e: &KScopeError) -> bool {
+    matches!(e, KScopeError::Config(_) | KScopeError::KeyFileLine { .. } | KScopeError::KeyFileField { .. })
+}
+
+/// The server refused our key or protocol version, if the notice is real.
+fn is_refusal(e: &KScopeError) -> bool {
+    matches!(e, KScopeError::Peer { code: ErrorCode::AuthFailed | ErrorCode::VersionUnsupported, .. })
+}
+
+/// Delay before reconnect attempt `attempt` (from 1): doubling from
+/// `INITIAL_BACKOFF` up to `max`, then shortened by a random amount of up
+/// to half, so clients cut off together do not all return at once.
+fn backoff(attempt: u64, max: Duration) -> Duration {
+    let doublings = attempt.saturating_sub(1).min(16) as u32;
+    let delay = INITIAL_BACKOFF.saturating_mul(1 << doublings).min(max);
+    delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+/// Sends a datagram to the server, treating an ICMP port unreachable from
+/// an earlier send as "server not up (yet)" rather than a fatal error: the
+/// handshake and keepalive timeouts decide when to give up. The kernel
+/// reports that error instead of sending, so the send is retried.
+async fn send(socket: &UdpSocket, message: &[u8]) -> Result<()> {
+    for _ in 0..2 {
+        match socket.send(message).await {
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                log::debug!("Server unreachable: {}", e);
+            }
+            res => return res.map(|_| ()).map_err(KScopeError::from),
+        }
+    }
+    Ok(())
+}
+
+/// Encrypts TUN packets to the server, dropping those for destinations
+/// outside `allowed_ips`. Packets queued while there was no session are
+/// stale and dropped too.
+async fn tun_to_udp(
+    tun_rx: TunQueue,
+    socket: Arc<UdpSocket>,
+    transport: Arc<Mutex<SecureTransport>>,
+    remote_id: u32,
+    allowed_ips: RoutingTable<()>,
+) -> Result<()> {
+    let mut tun_rx = tun_rx.lock().await;
+    while tun_rx.try_recv().is_ok() {}
+
+    while let Some(packet) = tun_rx.recv().await {
+        let dst = tun::packet_destination(&packet);
+        if !dst.is_some_and(|ip| allowed_ips.contains(&ip)) {
+            log::trace!("Dropping packet to {:?} outside allowed IPs", dst);
+            continue;
+        }
+        let datagram = transport.lock().unwrap().seal(&packet, remote_id)?;
+        send(&socket, &datagram).await?;
+    }
+
+    Err(KScopeError::Io("TUN reader stopped".into()))
+}
+
+/// Decrypts server packets into the TUN device, if their source is in
+/// `allowed_ips`, and applies pushed config. Ends with the server's error
+/// if it reports one for our session, or for "session unknown" once the
+/// server has also been silent for `NOTICE_GRACE`.
+async fn udp_to_tun(
+    socket: Arc<UdpSocket>,
+    tun: Arc<TunDevice>,
+    transport: Arc<Mutex<SecureTransport>>,
+    ids: SessionIds,
+    applier: Arc<ConfigApplier>,
+    allowed_ips: RoutingTable<()>,
+) -> Result<()> {
+    let server = socket.peer_addr()?;
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+
+    loop {
+        let n = match socket.recv_from(&mut buf).await {
+            Ok((n, from)) if from == server => n,
+            Ok((_, from)) => {
+                log::debug!("Dropping datagram from {}", from);
+                continue;
+            }
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                log::debug!("Server unreachable: {}", e);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let header = match PacketHeader::deserialize(&buf[..n]) {
+            Ok(header) => header,
+            Err(e) => {
+                log::debug!("Dropping {} byte datagram: {}", n, e);
+                continue;
+            }
+        };
+        if header.packet_type == PacketType::ErrorPacket {
+            match Packet::deserialize(&buf[..n]) {
+                // A notice echoing the server's ID answers one of our packets.
+                Ok((Packet::Error(notice), id)) if id == ids.local || id == ids.remote => {
+                    // It is not authenticated, so a session still hearing
+                    // from the server outlives a stale or forged one.
+                    let silence = transport.lock().unwrap().silence(Instant::now());
+                    if notice.code == ErrorCode::SessionUnknown && silence < NOTICE_GRACE {
+                        log::debug!("Ignoring \"{}\": server heard from {:?} ago", notice, silence);
+                        continue;
+                    }
+                    return Err(notice.into());
+                }
+                Ok((_, id)) => log::debug!("Ignoring error notice for session {:08x}", id),
+                Err(e) => log::debug!("Dropping malformed error notice: {}", e),
+            }
+            continue;
+        }
+        if header.packet_type != PacketType::TransportData {
+            log::debug!("Ignoring {:?} packet", header.packet_type);
+            continue;
+        }
+        if header.session_id != ids.local {
+            // Traffic for a session we no longer have, e.g. from before a
+            // restart: tell the server so it can drop it.
+            log::debug!("Dropping packet for session {:08x}", header.session_id);
+            let notice = Packet::Error(ErrorPacket::new(ErrorCode::SessionUnknown, ""));
+            send(&socket, &notice.serialize(header.session_id)).await?;
+            continue;
+        }
+        let packet = match TransportData::parse(&buf[..n]) {
+            Ok(packet) => packet,
+            Err(e) => {
+                log::debug!("Dropping malformed transport packet: {}", e);
+                continue;
+            }
+        };
+
+        let plain = match transport.lock().unwrap().open(&header, &packet) {
+            Ok(plain) => plain,
+            Err(e) => {
+                log::warn!("Dropping undecryptable packet: {}", e);
+                continue;
+            }
+        };
+        if !Control::is_control(&plain) {
+            let src = tun::packet_source(&plain);
+            if src.is_some_and(|ip| allowed_ips.contains(&ip)) {
+                tun.write(&plain)?;
+            } else {
+                log::debug!("Dropping packet from {:?} outside allowed IPs", src);
+            }
+            continue;
+        }
+
+        match Control::decode(&plain) {
+            Ok(Control::KeepAlive(probe)) => {
+                let echo = transport.lock().unwrap().seal(&Control::KeepAliveEcho(probe).encode(), ids.remote)?;
+                send(&socket, &echo).await?;
+            }
+            Ok(Control::KeepAliveEcho(echo)) => {
+                let mut transport = transport.lock().unwrap();
+                if let Some(rtt) = transport.keepalive_echoed(&echo, Instant::now()) {
+                    log::trace!("rtt {:?}, smoothed {:?}", rtt, transport.rtt().smoothed.unwrap_or(rtt));
+                }
+            }
+            Ok(Control::Config(config)) => applier.apply(&config)?,
+            Ok(Control::ConfigRequest) => log::debug!("Ignoring config request from server"),
+            Err(e) => log::debug!("Dropping control message: {}", e),
+        }
+    }
+}
+
+/// Sends keepalives while the session is idle and fails once the server
+/// has been silent for the keepalive timeout. Passes the transport to
+/// `report` on every tick.
+async fn keepalive(
+    socket: Arc<UdpSocket>,
+    transport: Arc<Mutex<SecureTransport>>,
+    remote_id: u32,
+    report: impl Fn(&SecureTransport),
+) -> Result<()> {
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        ticker.tick().await;
+        let now = Instant::now();
+        let probe = {
+            let mut transport = transport.lock().unwrap();
+            report(&transport);
+            if transport.is_peer_dead(now) {
+                return Err(KScopeError::Protocol(format!(
+                    "server silent for {}s",
+                    transport.silence(now).as_secs()
+                )));
+            }
+            transport.poll_keepalive(now, remote_id)?
+        };
+        if let Some(probe) = probe {
+            send(&socket, &probe).await?;
+        }
+    }
+}
+
+/// Asks again for the server's config until it arrives. A server that
+/// does not push config, or never answers, leaves the local settings.
+async fn request_config(
+    socket: Arc<UdpSocket>,
+    transport: Arc<Mutex<SecureTransport>>,
+    remote_id: u32,
+    applier: Arc<ConfigApplier>,
+) -> Result<()> {
+    if transport.lock().unwrap().capabilities().has(Capabilities::CONFIG_PUSH) {
+        for _ in 0..CONFIG_REQUESTS {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            if applier.is_applied() {
+                return Ok(());
+            }
+            let request = transport.lock().unwrap().seal(&Control::ConfigRequest.encode(), remote_id)?;
+            send(&socket, &request).await?;
+        }
+        log::warn!("Server sent no configuration, using local settings");
+    }
+    applier.apply(&TunnelConfig::default())
+}
@@ -0,0 +1,74 @@
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce, Tag};
+use std::error::Error;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+pub const KEY_LEN: usize = 32;
+pub const TAG_LEN: usize = 16;
+
+/// One direction of a transport session: a ChaCha20-Poly1305 key used with
+/// explicit 64-bit counters, laid out exactly like Noise's `ChaChaPoly`.
+#[derive(Zeroize, ZeroizeOnDrop)]
+pub struct CipherKey {
+    key: [u8; KEY_LEN],
+}
+
+impl CipherKey {
+    /// Takes ownership of `key`, wiping the caller's copy.
+    pub fn new(key: &mut [u8; KEY_LEN]) -> Self {
+        let this = Self { key: *key };
+        key.zeroize();
+        this
+    }
+
+    /// Seals `plain` into `out`, authenticating `aad` along with it.
+    pub fn encrypt(&self, nonce: u64, aad: &[u8], plain: &[u8], out: &mut [u8]) -> Result<usize, Box<dyn Error>> {
+        let len = plain.len();
+        if out.len() < len + TAG_LEN {
+            return Err("output buffer too small".into());
+        }
+
+        out[..len].copy_from_slice(plain);
+        let tag = self.aead()
+            .encrypt_in_place_detached(&Self::nonce(nonce), aad, &mut out[..len])
+            .map_err(|_| "encryption failed")?;
+        out[len..len + TAG_LEN].copy_from_slice(&tag);
+        Ok(len + TAG_LEN)
+    }
+
+    /// Opens `input` into `out`; fails unless `aad` matches what was sealed.
+    pub fn decrypt(&self, nonce: u64, aad: &[u8], input: &[u8], out: &mut [u8]) -> Result<usize, Box<dyn Error>> {
+        let Some(len) = input.len().checked_sub(TAG_LEN) else {
+            return Err("ciphertext shorter than tag".into());
+        };
+        if out.len() < len {
+            return Err("output buffer too small".into());
+        }
+
+        let (ciphertext, tag) = input.split_at(len);
+        out[..len].copy_from_slice(ciphertext);
+        self.aead()
+            .decrypt_in_place_detached(&Self::nonce(nonce), aad, &mut out[..len], Tag::from_slice(tag))
+            .map_err(|_| "decryption failed")?;
+        Ok(len)
+    }
+
+    /// Noise `REKEY(k)`: the first 32 bytes of `ENCRYPT(k, 2^64-1, "", zeros)`.
+    /// One-way, so earlier keys cannot be recovered from later ones.
+    pub fn rekey(&self) -> CipherKey {
+        let mut key = [0u8; KEY_LEN];
+        // Encrypting a fixed-size buffer in place cannot fail.
+        let _ = self.aead().encrypt_in_place_detached(&Self::nonce(u64::MAX), &[], &mut key);
+        CipherKey::new(&mut key)
+    }
+
+    fn aead(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(&self.key.into())
+    }
+
+    fn nonce(counter: u64) -> Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&counter.to_le_bytes());
+        nonce.into()
+    }
+}
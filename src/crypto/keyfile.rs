@@ -1,31 +1,231 @@
+use crate::crypto::sealed;
+use crate::crypto::secret::SecretKey;
+use crate::{KScopeError, Result};
+use base64::{engine::general_purpose, Engine};
 use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use zeroize::Zeroizing;
 
+pub const KEY_LEN: usize = 32;
+
+/// Passphrase for an encrypted key file. It stays in our environment:
+/// unsetting it is unsound once the runtime's threads are up. Commands we
+/// run are started without it (see `tun::command`).
+pub const PASSPHRASE_ENV: &str = "KSCOPE_KEY_PASSPHRASE";
+/// An open file descriptor to read the passphrase from instead.
+pub const PASSPHRASE_FD_ENV: &str = "KSCOPE_KEY_PASSPHRASE_FD";
+
+/// Keys read from a `KEY=base64` file. Blank lines and lines starting
+/// with `#` are ignored. The file may also be passphrase-protected (see
+/// `crypto::sealed`), in which case it is decrypted first.
+///
+/// ```text
+/// # this host
+/// PRIVATE=...
+/// # the server (on a client) or accepted clients (on a server)
+/// PEER_PUBLIC=...
+/// PSK=...
+/// ```
+#[derive(Debug)]
 pub struct LoadedKeys {
-    pub private: Vec<u8>,
-    pub peer_public: Vec<u8>,
-    pub psk: Vec<u8>,
+    pub private: SecretKey,
+    /// The first `PEER_PUBLIC` entry; on a client this is the server's key.
+    pub peer_public: Option<[u8; KEY_LEN]>,
+    /// Every `PEER_PUBLIC` entry in file order; on a server, the clients it accepts.
+    pub peers: Vec<[u8; KEY_LEN]>,
+    /// Absent when peers use per-peer PSKs from a registry, or none at all.
+    pub psk: Option<SecretKey>,
+}
+
+pub fn load_keys(path: &Path) -> Result<LoadedKeys> {
+    let text = Zeroizing::new(fs::read_to_string(path)
+        .map_err(|e| KScopeError::Io(format!("{}: {}", path.display(), e)))?);
+    warn_if_exposed(path);
+
+    if sealed::is_sealed(&text) {
+        let passphrase = read_passphrase(&format!("Passphrase for {}: ", path.display()))?;
+        let plain = sealed::open(&text, passphrase.as_bytes()).map_err(|e| KScopeError::KeyFileField {
+            path: path.display().to_string(),
+            field: "DATA",
+            reason: e.to_string(),
+        })?;
+        let text = std::str::from_utf8(&plain).map_err(|_| KScopeError::KeyFileField {
+            path: path.display().to_string(),
+            field: "DATA",
+            reason: "does not decrypt to text".into(),
+        })?;
+        return parse_keys(path, text);
+    }
+    parse_keys(path, &text)
 }
 
-pub fn load_keys(path: &str) -> LoadedKeys {
-    let text = fs::read_to_string(path).expect("key file");
+fn parse_keys(path: &Path, text: &str) -> Result<LoadedKeys> {
+    let line_error = |line: usize, reason: String| KScopeError::KeyFileLine {
+        path: path.display().to_string(),
+        line,
+        reason,
+    };
 
     let mut private = None;
-    let mut peer_public = None;
+    let mut peers = Vec::new();
     let mut psk = None;
 
-    for line in text.lines() {
-        let (k, v) = line.split_once('=').unwrap();
-        match k {
-            "PRIVATE" => private = Some(base64::decode(v).unwrap()),
-            "PEER_PUBLIC" => peer_public = Some(base64::decode(v).unwrap()),
-            "PSK" => psk = Some(base64::decode(v).unwrap()),
-            _ => {}
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (k, v) = line.split_once('=')
+            .ok_or_else(|| line_error(line_no, "expected KEY=value".into()))?;
+        let (k, v) = (k.trim(), v.trim());
+        let key = decode_key(k, v).map_err(|reason| line_error(line_no, reason))?;
+
+        let slot = match k {
+            "PRIVATE" => &mut private,
+            "PSK" => &mut psk,
+            "PEER_PUBLIC" => {
+                peers.push(*key.as_bytes());
+                continue;
+            }
+            _ => return Err(line_error(line_no, format!("unknown key {:?}", k))),
+        };
+        if slot.replace(key).is_some() {
+            return Err(line_error(line_no, format!("{} given more than once", k)));
+        }
+    }
+
+    let private = private.ok_or_else(|| KScopeError::KeyFileField {
+        path: path.display().to_string(),
+        field: "PRIVATE",
+        reason: "missing".into(),
+    })?;
+
+    Ok(LoadedKeys {
+        private,
+        peer_public: peers.first().copied(),
+        peers,
+        psk,
+    })
+}
+
+/// Reads a public key file: one base64 key, as `genkeys` writes to `.pub`.
+pub fn load_public_key(path: &Path) -> Result<[u8; KEY_LEN]> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| KScopeError::Io(format!("{}: {}", path.display(), e)))?;
+    let key = decode_key("public key", text.trim()).map_err(|reason| KScopeError::KeyFileLine {
+        path: path.display().to_string(),
+        line: 1,
+        reason,
+    })?;
+    Ok(*key.as_bytes())
+}
+
+fn decode_key(field: &str, value: &str) -> std::result::Result<SecretKey, String> {
+    let bytes = Zeroizing::new(general_purpose::STANDARD.decode(value)
+        .map_err(|e| format!("{} is not valid base64: {}", field, e))?);
+    SecretKey::from_slice(&bytes)
+        .ok_or_else(|| format!("{} must be {} bytes, got {}", field, KEY_LEN, bytes.len()))
+}
+
+/// Gets a passphrase from `PASSPHRASE_FD_ENV`, `PASSPHRASE_ENV` or, failing
+/// both, by prompting on the terminal.
+pub fn read_passphrase(prompt: &str) -> Result<Zeroizing<String>> {
+    if let Some(passphrase) = passphrase_from_env()? {
+        return Ok(passphrase);
+    }
+    let passphrase = rpassword::prompt_password(prompt)
+        .map_err(|e| KScopeError::Io(format!("cannot read passphrase: {}", e)))?;
+    Ok(Zeroizing::new(passphrase))
+}
+
+/// Like `read_passphrase`, but asks twice when prompting and rejects an
+/// empty passphrase. Used when writing a new encrypted file.
+pub fn read_new_passphrase() -> Result<Zeroizing<String>> {
+    let passphrase = match passphrase_from_env()? {
+        Some(passphrase) => passphrase,
+        None => {
+            let first = read_passphrase("New passphrase: ")?;
+            let again = read_passphrase("Repeat passphrase: ")?;
+            if *first != *again {
+                return Err(KScopeError::Config("passphrases do not match".into()));
+            }
+            first
+        }
+    };
+    if passphrase.is_empty() {
+        return Err(KScopeError::Config("passphrase must not be empty".into()));
+    }
+    Ok(passphrase)
+}
+
+/// Set once the descriptor in `PASSPHRASE_FD_ENV` has been read and closed,
+/// since the variable itself cannot be unset.
+static PASSPHRASE_FD_USED: AtomicBool = AtomicBool::new(false);
+
+fn passphrase_from_env() -> Result<Option<Zeroizing<String>>> {
+    if let Ok(fd) = std::env::var(PASSPHRASE_FD_ENV) {
+        if PASSPHRASE_FD_USED.swap(true, Ordering::SeqCst) {
+            return Err(KScopeError::Config(format!("{} was already read", PASSPHRASE_FD_ENV)));
         }
+        let fd = fd.parse()
+            .map_err(|_| KScopeError::Config(format!("{} must be a file descriptor number", PASSPHRASE_FD_ENV)))?;
+        return read_passphrase_fd(fd).map(Some);
+    }
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(Some(Zeroizing::new(passphrase)));
+    }
+    Ok(None)
+}
+
+#[cfg(unix)]
+fn read_passphrase_fd(fd: i32) -> Result<Zeroizing<String>> {
+    use nix::libc::{fcntl, F_GETFD};
+    use std::io::Read;
+    use std::os::fd::FromRawFd;
+
+    // Closing stdio, or a number that is not open and may be reused later,
+    // would break whatever else holds it.
+    if fd <= 2 {
+        return Err(KScopeError::Config(format!("{} must not be stdin, stdout or stderr", PASSPHRASE_FD_ENV)));
+    }
+    // SAFETY: F_GETFD only reads the descriptor flags.
+    if unsafe { fcntl(fd, F_GETFD) } == -1 {
+        return Err(KScopeError::Config(format!("{}: fd {} is not open", PASSPHRASE_FD_ENV, fd)));
     }
 
-    LoadedKeys {
-        private: private.unwrap(),
-        peer_public: peer_public.unwrap(),
-        psk: psk.unwrap(),
+    // SAFETY: the descriptor is open and was handed to us for exactly this
+    // purpose; we take ownership and close it once read.
+    let mut file = unsafe { fs::File::from_raw_fd(fd) };
+    let mut text = Zeroizing::new(String::new());
+    file.read_to_string(&mut text)
+        .map_err(|e| KScopeError::Io(format!("cannot read passphrase from fd {}: {}", fd, e)))?;
+    let line = text.lines().next().unwrap_or("");
+    Ok(Zeroizing::new(line.to_string()))
+}
+
+#[cfg(not(unix))]
+fn read_passphrase_fd(_fd: i32) -> Result<Zeroizing<String>> {
+    Err(KScopeError::Config(format!("{} is only supported on Unix", PASSPHRASE_FD_ENV)))
+}
+
+#[cfg(unix)]
+fn warn_if_exposed(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Ok(meta) = fs::metadata(path) {
+        let mode = meta.permissions().mode();
+        if mode & 0o077 != 0 {
+            log::warn!(
+                "Key file {} is accessible by group or others (mode {:o}); run chmod 600",
+                path.display(),
+                mode & 0o777
+            );
+        }
     }
 }
+
+#[cfg(not(unix))]
+fn warn_if_exposed(_path: &Path) {}
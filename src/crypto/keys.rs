@@ -1,3 +1,4 @@
+use crate::crypto::secret::SecretKey;
 use rand_core::OsRng;
 use x25519_dalek::{PublicKey, StaticSecret};
 
@@ -8,8 +9,31 @@ pub struct KeyPair {
 
 impl KeyPair {
     pub fn generate() -> Self {
-        let private = StaticSecret::new(OsRng);
+        let private = StaticSecret::random_from_rng(OsRng);
         let public = PublicKey::from(&private);
         Self { private, public }
     }
+
+    /// Rebuilds the key pair for an existing private key.
+    pub fn from_secret(secret: &SecretKey) -> Self {
+        let private = StaticSecret::from(*secret.as_bytes());
+        let public = PublicKey::from(&private);
+        Self { private, public }
+    }
+
+    /// The private key as a self-wiping `SecretKey`.
+    pub fn secret(&self) -> SecretKey {
+        SecretKey::new(self.private.to_bytes())
+    }
+}
+
+/// Short human-comparable form of a public key: the first 16 bytes of its
+/// BLAKE3 hash as eight colon-separated groups of four hex digits.
+pub fn fingerprint(public: &[u8; 32]) -> String {
+    let hash = blake3::hash(public);
+    hash.as_bytes()[..16]
+        .chunks(2)
+        .map(hex::encode)
+        .collect::<Vec<_>>()
+        .join(":")
 }
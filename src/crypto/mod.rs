@@ -1,3 +1,6 @@
 pub mod noise;
+pub mod cipher;
 pub mod keys;
 pub mod keyfile;
+pub mod sealed;
+pub mod secret;
@@ -1,80 +1,147 @@
-use snow::{Builder, HandshakeState, TransportState};
+use crate::crypto::cipher::CipherKey;
+use crate::crypto::secret::SecretKey;
+use base64::{engine::general_purpose, Engine};
+use snow::{Builder, HandshakeState};
 use std::error::Error;
+use std::sync::Arc;
+use zeroize::Zeroizing;
 
-const NOISE_PARAMS: &str = "Noise_XXpsk2_25519_ChaChaPoly_BLAKE2s";
+/// IK lets the responder learn the initiator's static key from the first
+/// message, so it can pick that peer's PSK before `psk2` is mixed in.
+const NOISE_PARAMS: &str = "Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s";
+
+/// PSK used by peers that have none configured, as in WireGuard.
+pub const NO_PSK: [u8; 32] = [0; 32];
+
+/// The two directional keys produced by a finished handshake.
+pub struct TransportKeys {
+    pub send: CipherKey,
+    pub recv: CipherKey,
+}
+
+/// Decides which initiators a responder accepts.
+pub trait PeerAuthorizer: Send + Sync {
+    /// Returns the PSK to use with the peer owning `public`, or `None` to
+    /// reject it before the handshake completes.
+    fn psk_for(&self, public: &[u8; 32]) -> Option<SecretKey>;
+}
 
 pub struct NoiseSession {
     handshake: Option<HandshakeState>,
-    transport: Option<TransportState>,
+    transport: Option<TransportKeys>,
+    authorizer: Option<Arc<dyn PeerAuthorizer>>,
+    remote_static: Option<[u8; 32]>,
 }
 
 impl NoiseSession {
+    /// Starts a handshake with the responder owning `remote_static`; IK
+    /// only completes if the responder proves ownership of that key.
     pub fn new_initiator(
-        static_private: &[u8],
-        remote_static: &[u8],
-        psk: &[u8],
+        static_private: &SecretKey,
+        remote_static: &[u8; 32],
+        psk: &SecretKey,
     ) -> Result<Self, Box<dyn Error>> {
         let handshake = Builder::new(NOISE_PARAMS.parse()?)
-            .local_private_key(static_private)
+            .local_private_key(static_private.as_bytes())
             .remote_public_key(remote_static)
-            .psk(2, psk)
+            .psk(2, psk.as_bytes())
             .build_initiator()?;
 
         Ok(Self {
             handshake: Some(handshake),
             transport: None,
+            authorizer: None,
+            remote_static: Some(*remote_static),
         })
     }
 
+    /// Starts a handshake that accepts any initiator `authorizer` knows,
+    /// using the PSK it returns for that peer.
     pub fn new_responder(
-        static_private: &[u8],
-        remote_static: &[u8],
-        psk: &[u8],
+        static_private: &SecretKey,
+        authorizer: Arc<dyn PeerAuthorizer>,
     ) -> Result<Self, Box<dyn Error>> {
         let handshake = Builder::new(NOISE_PARAMS.parse()?)
-            .local_private_key(static_private)
-            .remote_public_key(remote_static)
-            .psk(2, psk)
+            .local_private_key(static_private.as_bytes())
             .build_responder()?;
 
         Ok(Self {
             handshake: Some(handshake),
             transport: None,
+            authorizer: Some(authorizer),
+            remote_static: None,
         })
     }
 
+    /// Checks the initiator's static key as soon as the handshake reveals
+    /// it and installs its PSK before the response is written.
+    fn authorize_remote(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(authorizer) = self.authorizer.as_ref() else { return Ok(()) };
+        if self.remote_static.is_some() {
+            return Ok(());
+        }
+        let Some(hs) = self.handshake.as_mut() else { return Ok(()) };
+        let Some(rs) = hs.get_remote_static().and_then(|rs| <[u8; 32]>::try_from(rs).ok()) else {
+            return Ok(());
+        };
+
+        let Some(psk) = authorizer.psk_for(&rs) else {
+            self.handshake = None;
+            let key = general_purpose::STANDARD.encode(rs);
+            return Err(format!("peer key {} is not authorized", key).into());
+        };
+        hs.set_psk(2, psk.as_bytes())?;
+        self.remote_static = Some(rs);
+        Ok(())
+    }
+
     fn finish_if_complete(&mut self) -> Result<(), Box<dyn Error>> {
         if let Some(hs) = self.handshake.as_ref() {
             if hs.is_handshake_finished() {
-                let hs = self.handshake.take().unwrap();
-                self.transport = Some(hs.into_transport_mode()?);
+                let mut hs = self.handshake.take().unwrap();
+                let (mut initiator_key, mut responder_key) = hs.dangerously_get_raw_split();
+                let (send, recv) = if hs.is_initiator() {
+                    (&mut initiator_key, &mut responder_key)
+                } else {
+                    (&mut responder_key, &mut initiator_key)
+                };
+                self.transport = Some(TransportKeys {
+                    send: CipherKey::new(send),
+                    recv: CipherKey::new(recv),
+                });
             }
         }
         Ok(())
     }
 
-    pub fn write_handshake(&mut self, out: &mut [u8]) -> Result<usize, Box<dyn Error>> {
-        let n = self.handshake.as_mut().unwrap().write_message(&[], out)?;
+    pub fn write_handshake(&mut self, payload: &[u8], out: &mut [u8]) -> Result<usize, Box<dyn Error>> {
+        let n = self.handshake.as_mut().ok_or("handshake already finished")?.write_message(payload, out)?;
         self.finish_if_complete()?;
         Ok(n)
     }
 
-    pub fn read_handshake(&mut self, input: &[u8]) -> Result<(), Box<dyn Error>> {
-        let mut tmp = [0u8; 1024];
-        self.handshake.as_mut().unwrap().read_message(input, &mut tmp)?;
+    /// Reads the next handshake message and returns its payload. A message
+    /// that fails to decrypt leaves the handshake state untouched.
+    pub fn read_handshake(&mut self, input: &[u8]) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+        let mut payload = Zeroizing::new(vec![0u8; input.len()]);
+        let n = self.handshake.as_mut().ok_or("handshake already finished")?.read_message(input, &mut payload)?;
+        payload.truncate(n);
+        self.authorize_remote()?;
         self.finish_if_complete()?;
-        Ok(())
+        Ok(payload)
     }
 
-    pub fn encrypt(&mut self, plain: &[u8], out: &mut [u8]) -> Result<usize, Box<dyn Error>> {
-        Ok(self.transport.as_mut().unwrap().write_message(plain, out)?)
+    pub fn is_ready(&self) -> bool {
+        self.transport.is_some()
     }
 
-    pub fn decrypt(&mut self, input: &[u8], out: &mut [u8]) -> Result<usize, Box<dyn Error>> {
-        Ok(self.transport.as_mut().unwrap().read_message(input, out)?)
+    /// The peer's static public key: known up front on the initiator, and
+    /// once authorized on the responder.
+    pub fn remote_static(&self) -> Option<&[u8; 32]> {
+        self.remote_static.as_ref()
     }
 
-    pub fn is_ready(&self) -> bool {
-        self.transport.is_some()
+    pub fn into_transport_keys(self) -> Result<TransportKeys, Box<dyn Error>> {
+        self.transport.ok_or_else(|| "handshake not complete".into())
     }
 }
@@ -0,0 +1,156 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{engine::general_purpose, Engine};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use rand::RngCore;
+use std::error::Error;
+use zeroize::Zeroizing;
+
+/// First line of a passphrase-protected key file.
+pub const HEADER: &str = "# kscope encrypted keys v1";
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Argon2id cost used for new files: 64 MiB, 3 passes, 1 lane.
+const M_COST: u32 = 64 * 1024;
+const T_COST: u32 = 3;
+const P_COST: u32 = 1;
+
+/// Refuse files asking for more than 1 GiB, 64 passes or 16 lanes so a
+/// tampered file cannot exhaust memory, CPU or threads before the
+/// passphrase is even checked.
+const MAX_M_COST: u32 = 1024 * 1024;
+const MAX_T_COST: u32 = 64;
+const MAX_P_COST: u32 = 16;
+
+/// Whether `text` is a key file written by `seal`.
+pub fn is_sealed(text: &str) -> bool {
+    text.lines().next().is_some_and(|l| l.trim() == HEADER)
+}
+
+/// Encrypts a plain key file under `passphrase`.
+///
+/// ```text
+/// # kscope encrypted keys v1
+/// KDF=argon2id:m=65536,t=3,p=1
+/// SALT=base64
+/// NONCE=base64
+/// DATA=base64
+/// ```
+///
+/// The header and KDF line are authenticated along with the sealed keys.
+pub fn seal(plain: &[u8], passphrase: &[u8]) -> Result<String, Box<dyn Error>> {
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let kdf = format!("argon2id:m={},t={},p={}", M_COST, T_COST, P_COST);
+    let key = derive_key(passphrase, &salt, M_COST, T_COST, P_COST)?;
+    let aad = associated_data(&kdf);
+    let data = ChaCha20Poly1305::new(key.as_ref().into())
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: plain, aad: aad.as_bytes() })
+        .map_err(|_| "encryption failed")?;
+
+    let b64 = &general_purpose::STANDARD;
+    Ok(format!(
+        "{}\nKDF={}\nSALT={}\nNONCE={}\nDATA={}\n",
+        HEADER,
+        kdf,
+        b64.encode(salt),
+        b64.encode(nonce),
+        b64.encode(data)
+    ))
+}
+
+/// Decrypts a file written by `seal`, returning the plain key file.
+pub fn open(text: &str, passphrase: &[u8]) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+    let mut kdf = None;
+    let mut salt = None;
+    let mut nonce = None;
+    let mut data = None;
+
+    for line in text.lines().skip(1) {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (k, v) = line.split_once('=').ok_or("expected KEY=value")?;
+        let slot = match k.trim() {
+            "KDF" => &mut kdf,
+            "SALT" => &mut salt,
+            "NONCE" => &mut nonce,
+            "DATA" => &mut data,
+            other => return Err(format!("unknown field {:?}", other).into()),
+        };
+        *slot = Some(v.trim());
+    }
+
+    let kdf = kdf.ok_or("missing KDF")?;
+    let (m, t, p) = parse_kdf(kdf)?;
+    if m > MAX_M_COST {
+        return Err(format!("KDF memory cost {} KiB is too large", m).into());
+    }
+    if t > MAX_T_COST {
+        return Err(format!("KDF time cost {} is too large", t).into());
+    }
+    if p > MAX_P_COST {
+        return Err(format!("KDF parallelism {} is too large", p).into());
+    }
+
+    let b64 = &general_purpose::STANDARD;
+    let salt = b64.decode(salt.ok_or("missing SALT")?).map_err(|e| format!("SALT: {}", e))?;
+    let nonce = b64.decode(nonce.ok_or("missing NONCE")?).map_err(|e| format!("NONCE: {}", e))?;
+    let data = b64.decode(data.ok_or("missing DATA")?).map_err(|e| format!("DATA: {}", e))?;
+    if nonce.len() != NONCE_LEN {
+        return Err(format!("NONCE must be {} bytes", NONCE_LEN).into());
+    }
+
+    let key = derive_key(passphrase, &salt, m, t, p)?;
+    let aad = associated_data(kdf);
+    let plain = ChaCha20Poly1305::new(key.as_ref().into())
+        .decrypt(Nonce::from_slice(&nonce), Payload { msg: &data, aad: aad.as_bytes() })
+        .map_err(|_| "wrong passphrase or corrupted file")?;
+    Ok(Zeroizing::new(plain))
+}
+
+fn associated_data(kdf: &str) -> String {
+    format!("{}\nKDF={}", HEADER, kdf)
+}
+
+fn parse_kdf(kdf: &str) -> Result<(u32, u32, u32), Box<dyn Error>> {
+    let params = kdf.strip_prefix("argon2id:").ok_or("unsupported KDF")?;
+
+    let (mut m, mut t, mut p) = (None, None, None);
+    for param in params.split(',') {
+        let (k, v) = param.split_once('=').ok_or("malformed KDF parameters")?;
+        let v: u32 = v.parse().map_err(|_| format!("bad KDF parameter {}", k))?;
+        match k {
+            "m" => m = Some(v),
+            "t" => t = Some(v),
+            "p" => p = Some(v),
+            _ => return Err(format!("unknown KDF parameter {}", k).into()),
+        }
+    }
+
+    match (m, t, p) {
+        (Some(m), Some(t), Some(p)) => Ok((m, t, p)),
+        _ => Err("incomplete KDF parameters".into()),
+    }
+}
+
+fn derive_key(
+    passphrase: &[u8],
+    salt: &[u8],
+    m: u32,
+    t: u32,
+    p: u32,
+) -> Result<Zeroizing<[u8; 32]>, Box<dyn Error>> {
+    let params = Params::new(m, t, p, Some(32)).map_err(|e| format!("KDF parameters: {}", e))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase, salt, key.as_mut())
+        .map_err(|e| format!("KDF: {}", e))?;
+    Ok(key)
+}
@@ -0,0 +1,40 @@
+use std::fmt;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+pub const SECRET_LEN: usize = 32;
+
+/// A 32-byte private key or PSK. Wiped when dropped and never printed.
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub struct SecretKey([u8; SECRET_LEN]);
+
+impl SecretKey {
+    pub fn new(bytes: [u8; SECRET_LEN]) -> Self {
+        Self(bytes)
+    }
+
+    /// Copies `bytes` if it is exactly 32 bytes long.
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != SECRET_LEN {
+            return None;
+        }
+        let mut key = [0u8; SECRET_LEN];
+        key.copy_from_slice(bytes);
+        Some(Self(key))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; SECRET_LEN] {
+        &self.0
+    }
+}
+
+impl From<[u8; SECRET_LEN]> for SecretKey {
+    fn from(bytes: [u8; SECRET_LEN]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey(<redacted>)")
+    }
+}
@@ -1,7 +1,12 @@
+pub mod client;
 pub mod crypto;
+pub mod net;
+pub mod network;
 pub mod protocol;
+pub mod server;
 pub mod tun;
 
+use protocol::packet::ErrorCode;
 use std::fmt;
 
 #[derive(Debug)]
@@ -9,6 +14,12 @@ pub enum KScopeError {
     Io(String),
     Protocol(String),
     Config(String),
+    /// A key file line that could not be parsed (1-based line number).
+    KeyFileLine { path: String, line: usize, reason: String },
+    /// A key file field that is missing or unusable as a whole.
+    KeyFileField { path: String, field: &'static str, reason: String },
+    /// The peer sent an `ErrorPacket`.
+    Peer { code: ErrorCode, message: String },
 }
 
 impl From<std::io::Error> for KScopeError {
@@ -17,12 +28,35 @@ impl From<std::io::Error> for KScopeError {
     }
 }
 
+impl From<Box<dyn std::error::Error>> for KScopeError {
+    fn from(e: Box<dyn std::error::Error>) -> Self {
+        match e.downcast::<KScopeError>() {
+            Ok(e) => *e,
+            Err(e) => KScopeError::Protocol(e.to_string()),
+        }
+    }
+}
+
+impl From<protocol::packet::ErrorPacket> for KScopeError {
+    fn from(e: protocol::packet::ErrorPacket) -> Self {
+        KScopeError::Peer { code: e.code, message: e.message }
+    }
+}
+
 impl fmt::Display for KScopeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             KScopeError::Io(s) => write!(f, "IO error: {}", s),
             KScopeError::Protocol(s) => write!(f, "Protocol error: {}", s),
             KScopeError::Config(s) => write!(f, "Config error: {}", s),
+            KScopeError::KeyFileLine { path, line, reason } => {
+                write!(f, "Key file error: {}:{}: {}", path, line, reason)
+            }
+            KScopeError::KeyFileField { path, field, reason } => {
+                write!(f, "Key file error: {}: {} {}", path, field, reason)
+            }
+            KScopeError::Peer { code, message } if message.is_empty() => write!(f, "Peer error: {}", code),
+            KScopeError::Peer { code, message } => write!(f, "Peer error: {}: {}", code, message),
         }
     }
 }
@@ -0,0 +1,86 @@
+use crate::KScopeError;
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An IPv4 or IPv6 network in `addr/prefix` notation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpCidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> crate::Result<Self> {
+        if prefix > Self::max_prefix(&addr) {
+            return Err(KScopeError::Config(format!("prefix /{} too long for {}", prefix, addr)));
+        }
+        Ok(Self { addr, prefix })
+    }
+
+    /// A single-address network (`/32` or `/128`).
+    pub fn host(addr: IpAddr) -> Self {
+        Self { addr, prefix: Self::max_prefix(&addr) }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// Whether this is a single address rather than a network.
+    pub fn is_host(&self) -> bool {
+        self.prefix == Self::max_prefix(&self.addr)
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+
+    fn max_prefix(addr: &IpAddr) -> u8 {
+        if addr.is_ipv4() { 32 } else { 128 }
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = KScopeError;
+
+    fn from_str(s: &str) -> crate::Result<Self> {
+        let invalid = || KScopeError::Config(format!("invalid CIDR: {}", s));
+        match s.split_once('/') {
+            Some((addr, prefix)) => {
+                let addr = addr.trim().parse().map_err(|_| invalid())?;
+                let prefix = prefix.trim().parse().map_err(|_| invalid())?;
+                Self::new(addr, prefix)
+            }
+            None => Ok(Self::host(s.trim().parse().map_err(|_| invalid())?)),
+        }
+    }
+}
+
+impl fmt::Display for IpCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpCidr {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
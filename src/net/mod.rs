@@ -1 +1,3 @@
+pub mod cidr;
+pub mod routing;
 pub mod tun;
@@ -0,0 +1,119 @@
+use crate::net::cidr::IpCidr;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// Networks mapped to values, looked up by longest prefix match.
+///
+/// Each address family keeps one hash map per prefix length, so a lookup
+/// is at most one probe per length in use, longest first.
+#[derive(Debug, Clone)]
+pub struct RoutingTable<T> {
+    v4: Vec<HashMap<u128, T>>,
+    v6: Vec<HashMap<u128, T>>,
+}
+
+impl<T> Default for RoutingTable<T> {
+    fn default() -> Self {
+        Self {
+            v4: (0..=32).map(|_| HashMap::new()).collect(),
+            v6: (0..=128).map(|_| HashMap::new()).collect(),
+        }
+    }
+}
+
+impl<T> RoutingTable<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maps `network` to `value`, returning the value it replaces.
+    pub fn insert(&mut self, network: IpCidr, value: T) -> Option<T> {
+        let key = masked(&network.addr(), network.prefix());
+        self.family_mut(&network.addr())[network.prefix() as usize].insert(key, value)
+    }
+
+    pub fn remove(&mut self, network: &IpCidr) -> Option<T> {
+        let key = masked(&network.addr(), network.prefix());
+        self.family_mut(&network.addr())[network.prefix() as usize].remove(&key)
+    }
+
+    /// The value of the most specific network containing `ip`.
+    pub fn lookup(&self, ip: &IpAddr) -> Option<&T> {
+        let family = if ip.is_ipv4() { &self.v4 } else { &self.v6 };
+        family.iter().enumerate().rev()
+            .filter(|(_, entries)| !entries.is_empty())
+            .find_map(|(prefix, entries)| entries.get(&masked(ip, prefix as u8)))
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.lookup(ip).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.v4.iter().chain(&self.v6).map(HashMap::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn family_mut(&mut self, ip: &IpAddr) -> &mut Vec<HashMap<u128, T>> {
+        if ip.is_ipv4() { &mut self.v4 } else { &mut self.v6 }
+    }
+}
+
+impl<T> FromIterator<(IpCidr, T)> for RoutingTable<T> {
+    fn from_iter<I: IntoIterator<Item = (IpCidr, T)>>(iter: I) -> Self {
+        let mut table = Self::new();
+        for (network, value) in iter {
+            table.insert(network, value);
+        }
+        table
+    }
+}
+
+/// `ip` with all but its first `prefix` bits cleared, as an integer.
+fn masked(ip: &IpAddr, prefix: u8) -> u128 {
+    let (value, bits) = match ip {
+        IpAddr::V4(a) => (u32::from(*a) as u128, 32),
+        IpAddr::V6(a) => (u128::from(*a), 128),
+    };
+    let host_bits = bits - prefix as u32;
+    value.checked_shr(host_bits).and_then(|v| v.checked_shl(host_bits)).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn table(entries: &[(&str, u8)]) -> RoutingTable<u8> {
+        entries.iter().map(|(network, value)| (network.parse().unwrap(), *value)).collect()
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let table = table(&[("10.0.0.0/8", 1), ("10.8.0.0/24", 2), ("10.8.0.7/32", 3), ("0.0.0.0/0", 4)]);
+        assert_eq!(table.lookup(&ip("10.8.0.7")), Some(&3));
+        assert_eq!(table.lookup(&ip("10.8.0.8")), Some(&2));
+        assert_eq!(table.lookup(&ip("10.9.0.1")), Some(&1));
+        assert_eq!(table.lookup(&ip("192.0.2.1")), Some(&4));
+        assert_eq!(table.lookup(&ip("::1")), None);
+    }
+
+    #[test]
+    fn ipv6() {
+        let mut table = table(&[("fd00::/64", 1), ("fd00::7/128", 2)]);
+        assert_eq!(table.lookup(&ip("fd00::7")), Some(&2));
+        assert_eq!(table.lookup(&ip("fd00::8")), Some(&1));
+        assert_eq!(table.lookup(&ip("fd00:0:0:1::7")), None);
+        assert_eq!(table.lookup(&ip("10.0.0.7")), None);
+
+        assert_eq!(table.remove(&"fd00::7/128".parse().unwrap()), Some(2));
+        assert_eq!(table.lookup(&ip("fd00::7")), Some(&1));
+        assert_eq!(table.len(), 1);
+    }
+}
@@ -10,6 +10,7 @@ pub trait PacketHandler: Send + Sync {
 }
 
 /// Простой эхо-обработчик для тестирования
+#[derive(Default)]
 pub struct EchoHandler {
     packet_count: u64,
 }
@@ -14,7 +14,7 @@ impl UdpTransport {
     /// Создает сервер, который слушает на указанном адресе
     pub async fn bind(addr: &str, handler: Box<dyn PacketHandler>) -> Result<Self> {
         let socket = UdpSocket::bind(addr).await
-            .map_err(|e| KScopeError::Io(e))?;
+            .map_err(KScopeError::from)?;
         
         println!("[UDP] Server listening on {}", addr);
         Ok(Self { socket, handler })
@@ -23,10 +23,10 @@ impl UdpTransport {
     /// Создает клиент, который подключается к серверу
     pub async fn connect(server_addr: &str, handler: Box<dyn PacketHandler>) -> Result<Self> {
         let socket = UdpSocket::bind("0.0.0.0:0").await
-            .map_err(|e| KScopeError::Io(e))?;
+            .map_err(KScopeError::from)?;
         
         socket.connect(server_addr).await
-            .map_err(|e| KScopeError::Io(e))?;
+            .map_err(KScopeError::from)?;
         
         println!("[UDP] Client connected to {}", server_addr);
         Ok(Self { socket, handler })
@@ -58,7 +58,7 @@ impl UdpTransport {
                     }
                 }
                 Err(e) => {
-                    return Err(KScopeError::Io(e));
+                    return Err(KScopeError::from(e));
                 }
             }
         }
@@ -67,18 +67,18 @@ impl UdpTransport {
     /// Отправляет данные (для клиента)
     pub async fn send(&self, data: &[u8]) -> Result<usize> {
         self.socket.send(data).await
-            .map_err(|e| KScopeError::Io(e))
+            .map_err(KScopeError::from)
     }
     
     /// Отправляет данные на конкретный адрес (для сервера)
     pub async fn send_to(&self, data: &[u8], addr: SocketAddr) -> Result<usize> {
         self.socket.send_to(data, addr).await
-            .map_err(|e| KScopeError::Io(e))
+            .map_err(KScopeError::from)
     }
     
     /// Возвращает локальный адрес сокета
     pub fn local_addr(&self) -> Result<SocketAddr> {
         self.socket.local_addr()
-            .map_err(|e| KScopeError::Io(e))
+            .map_err(KScopeError::from)
     }
 }
@@ -0,0 +1,117 @@
+use crate::protocol::packet::SUPPORTED_VERSIONS;
+use std::error::Error;
+
+/// Length of the capability block as first defined. Later versions may
+/// append fields; decoders ignore bytes they do not know.
+pub const CAPABILITIES_LEN: usize = 10;
+
+const FLAG_PADDING: u8 = 0x01;
+
+/// What one side supports, exchanged inside the encrypted handshake
+/// payloads. Each side sends its own set and both compute the same
+/// `intersect`, so neither has to trust the other's choice.
+///
+/// ```text
+/// 0      min_version
+/// 1      max_version
+/// 2      compression algorithms (bitmask)
+/// 3      flags (bit 0: padding)
+/// 4..6   MTU (u16 BE)
+/// 6..10  features (u32 BE)
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub min_version: u8,
+    pub max_version: u8,
+    /// Supported compression algorithms, one bit each. None are defined yet.
+    pub compression: u8,
+    /// Whether transport payloads may be padded to hide their length.
+    pub padding: bool,
+    /// Largest inner packet this side can take.
+    pub mtu: u16,
+    /// Optional protocol features, one bit each; see `Capabilities::KEEPALIVE`.
+    pub features: u32,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            min_version: *SUPPORTED_VERSIONS.start(),
+            max_version: *SUPPORTED_VERSIONS.end(),
+            compression: 0,
+            padding: false,
+            mtu: 1420,
+            features: Self::KEEPALIVE | Self::CONFIG_PUSH,
+        }
+    }
+}
+
+impl Capabilities {
+    /// Encrypted keepalive probes and echoes.
+    pub const KEEPALIVE: u32 = 1 << 0;
+    /// Server-pushed `TunnelConfig` after the handshake.
+    pub const CONFIG_PUSH: u32 = 1 << 1;
+
+    /// Our capabilities for a tunnel with the given MTU.
+    pub fn with_mtu(mtu: u16) -> Self {
+        Self { mtu, ..Self::default() }
+    }
+
+    pub fn encode(&self) -> [u8; CAPABILITIES_LEN] {
+        let mut b = [0u8; CAPABILITIES_LEN];
+        b[0] = self.min_version;
+        b[1] = self.max_version;
+        b[2] = self.compression;
+        b[3] = if self.padding { FLAG_PADDING } else { 0 };
+        b[4..6].copy_from_slice(&self.mtu.to_be_bytes());
+        b[6..10].copy_from_slice(&self.features.to_be_bytes());
+        b
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let b = data.get(..CAPABILITIES_LEN)
+            .ok_or_else(|| format!("capabilities truncated: {} of {} bytes", data.len(), CAPABILITIES_LEN))?;
+        let caps = Self {
+            min_version: b[0],
+            max_version: b[1],
+            compression: b[2],
+            padding: b[3] & FLAG_PADDING != 0,
+            mtu: u16::from_be_bytes([b[4], b[5]]),
+            features: u32::from_be_bytes([b[6], b[7], b[8], b[9]]),
+        };
+        if caps.min_version > caps.max_version {
+            return Err(format!("empty version range {}-{}", caps.min_version, caps.max_version).into());
+        }
+        Ok(caps)
+    }
+
+    /// What both sides support: the highest common version, the common
+    /// compression algorithms and features, padding only if both allow it,
+    /// and the smaller MTU. Fails if no version is common to both.
+    pub fn intersect(&self, peer: &Capabilities) -> Result<Capabilities, Box<dyn Error>> {
+        let version = self.max_version.min(peer.max_version);
+        if version < self.min_version.max(peer.min_version) {
+            return Err(format!(
+                "no common protocol version (ours {}-{}, peer's {}-{})",
+                self.min_version, self.max_version, peer.min_version, peer.max_version
+            ).into());
+        }
+        Ok(Capabilities {
+            min_version: version,
+            max_version: version,
+            compression: self.compression & peer.compression,
+            padding: self.padding && peer.padding,
+            mtu: self.mtu.min(peer.mtu),
+            features: self.features & peer.features,
+        })
+    }
+
+    /// The protocol version of a negotiated set.
+    pub fn version(&self) -> u8 {
+        self.max_version
+    }
+
+    pub fn has(&self, feature: u32) -> bool {
+        self.features & feature == feature
+    }
+}
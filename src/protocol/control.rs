@@ -0,0 +1,158 @@
+use crate::net::cidr::IpCidr;
+use crate::protocol::packet::KeepAlive;
+use crate::KScopeError;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Largest first byte of a control message. IP packets start with version
+/// nibble 4 or 6, so a plaintext starting at or below this is never one.
+const MAX_CONTROL_TYPE: u8 = 0x3F;
+
+const KEEPALIVE: u8 = 0x01;
+const KEEPALIVE_ECHO: u8 = 0x02;
+const CONFIG: u8 = 0x03;
+const CONFIG_REQUEST: u8 = 0x04;
+
+const HAS_ADDRESS: u8 = 0x01;
+
+/// Tunnel settings the server assigns a client.
+///
+/// ```text
+/// flags u8 (bit 0: address present)
+/// [address: ip, prefix u8]
+/// mtu u16 BE
+/// dns count u8, ip * count
+/// routes count u8, (ip, prefix u8) * count
+/// ip = family u8 (4 or 6) followed by 4 or 16 address bytes
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TunnelConfig {
+    /// The client's tunnel address, with the prefix of the tunnel network.
+    pub address: Option<IpCidr>,
+    pub mtu: u16,
+    pub dns: Vec<IpAddr>,
+    pub routes: Vec<IpCidr>,
+}
+
+impl TunnelConfig {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(if self.address.is_some() { HAS_ADDRESS } else { 0 });
+        if let Some(address) = &self.address {
+            put_cidr(out, address);
+        }
+        out.extend_from_slice(&self.mtu.to_be_bytes());
+        out.push(self.dns.len().min(u8::MAX as usize) as u8);
+        for ip in self.dns.iter().take(u8::MAX as usize) {
+            put_ip(out, ip);
+        }
+        out.push(self.routes.len().min(u8::MAX as usize) as u8);
+        for route in self.routes.iter().take(u8::MAX as usize) {
+            put_cidr(out, route);
+        }
+    }
+
+    fn decode(mut data: &[u8]) -> crate::Result<Self> {
+        let d = &mut data;
+        let flags = take(d, 1)?[0];
+        let address = if flags & HAS_ADDRESS != 0 { Some(take_cidr(d)?) } else { None };
+        let mtu = u16::from_be_bytes(take(d, 2)?.try_into().unwrap());
+        let dns = (0..take(d, 1)?[0]).map(|_| take_ip(d)).collect::<crate::Result<_>>()?;
+        let routes = (0..take(d, 1)?[0]).map(|_| take_cidr(d)).collect::<crate::Result<_>>()?;
+        Ok(Self { address, mtu, dns, routes })
+    }
+}
+
+/// Messages between the peers themselves, sent inside encrypted transport
+/// packets in place of an IP packet: a type byte followed by the body.
+#[derive(Debug, Clone)]
+pub enum Control {
+    /// A probe the receiver must echo back unchanged.
+    KeepAlive(KeepAlive),
+    /// The answer to a `KeepAlive`.
+    KeepAliveEcho(KeepAlive),
+    /// Settings pushed by the server after the handshake.
+    Config(TunnelConfig),
+    /// Asks the server to send its `Config` again, e.g. after the first
+    /// one was lost.
+    ConfigRequest,
+}
+
+impl Control {
+    /// Whether a decrypted transport payload is a control message rather
+    /// than an IP packet for the tunnel.
+    pub fn is_control(plain: &[u8]) -> bool {
+        plain.first().is_some_and(|b| *b <= MAX_CONTROL_TYPE)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self {
+            Control::KeepAlive(ka) => {
+                out.push(KEEPALIVE);
+                out.extend_from_slice(&ka.to_bytes());
+            }
+            Control::KeepAliveEcho(ka) => {
+                out.push(KEEPALIVE_ECHO);
+                out.extend_from_slice(&ka.to_bytes());
+            }
+            Control::Config(config) => {
+                out.push(CONFIG);
+                config.encode(&mut out);
+            }
+            Control::ConfigRequest => out.push(CONFIG_REQUEST),
+        }
+        out
+    }
+
+    pub fn decode(plain: &[u8]) -> crate::Result<Self> {
+        let (&kind, body) = plain.split_first()
+            .ok_or_else(|| KScopeError::Protocol("Empty control message".into()))?;
+        match kind {
+            KEEPALIVE => Ok(Control::KeepAlive(KeepAlive::from_bytes(body)?)),
+            KEEPALIVE_ECHO => Ok(Control::KeepAliveEcho(KeepAlive::from_bytes(body)?)),
+            CONFIG => Ok(Control::Config(TunnelConfig::decode(body)?)),
+            CONFIG_REQUEST => Ok(Control::ConfigRequest),
+            _ => Err(KScopeError::Protocol(format!("Unknown control message type: {}", kind))),
+        }
+    }
+}
+
+fn put_ip(out: &mut Vec<u8>, ip: &IpAddr) {
+    match ip {
+        IpAddr::V4(v4) => {
+            out.push(4);
+            out.extend_from_slice(&v4.octets());
+        }
+        IpAddr::V6(v6) => {
+            out.push(6);
+            out.extend_from_slice(&v6.octets());
+        }
+    }
+}
+
+fn put_cidr(out: &mut Vec<u8>, cidr: &IpCidr) {
+    put_ip(out, &cidr.addr());
+    out.push(cidr.prefix());
+}
+
+fn take<'a>(data: &mut &'a [u8], n: usize) -> crate::Result<&'a [u8]> {
+    if data.len() < n {
+        return Err(KScopeError::Protocol("Tunnel config truncated".into()));
+    }
+    let (head, rest) = data.split_at(n);
+    *data = rest;
+    Ok(head)
+}
+
+fn take_ip(data: &mut &[u8]) -> crate::Result<IpAddr> {
+    match take(data, 1)?[0] {
+        4 => Ok(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(take(data, 4)?).unwrap()))),
+        6 => Ok(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(take(data, 16)?).unwrap()))),
+        family => Err(KScopeError::Protocol(format!("Unknown address family: {}", family))),
+    }
+}
+
+fn take_cidr(data: &mut &[u8]) -> crate::Result<IpCidr> {
+    let addr = take_ip(data)?;
+    let prefix = take(data, 1)?[0];
+    IpCidr::new(addr, prefix).map_err(|e| KScopeError::Protocol(e.to_string()))
+}
@@ -1,41 +1,280 @@
-use crate::crypto::noise::NoiseSession;
+use crate::crypto::noise::{NoiseSession, PeerAuthorizer};
+use crate::crypto::secret::SecretKey;
+use crate::protocol::capabilities::Capabilities;
+use crate::protocol::packet::{HandshakeInit, HandshakeResponse, Packet};
+use bytes::Bytes;
+use rand::rngs::OsRng;
+use rand::{Rng, RngCore};
 use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+/// Length of the initiation timestamp carried in the first message.
+const TIMESTAMP_LEN: usize = 8;
+
+/// When the initiator retransmits and when either side gives up.
+#[derive(Debug, Clone, Copy)]
+pub struct HandshakeTimers {
+    /// Delay before the first retransmission; doubled on each attempt.
+    pub retransmit: Duration,
+    pub max_retransmit: Duration,
+    /// Total time allowed from the first message to completion.
+    pub timeout: Duration,
+}
+
+impl Default for HandshakeTimers {
+    fn default() -> Self {
+        Self {
+            retransmit: Duration::from_secs(1),
+            max_retransmit: Duration::from_secs(8),
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl HandshakeTimers {
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self { timeout, ..Self::default() }
+    }
+
+    /// Backoff before retransmission number `attempt` (1-based), with
+    /// ±25% jitter so clients that lost the server together do not retry
+    /// in lockstep.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let base = self.retransmit
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+            .min(self.max_retransmit);
+        base.mul_f64(rand::thread_rng().gen_range(0.75..1.25))
+    }
+}
+
+/// What the initiator needs to start over with a fresh ephemeral key.
+struct InitiatorKeys {
+    private: SecretKey,
+    remote: [u8; 32],
+    psk: SecretKey,
+}
+
+/// A Noise IK handshake plus the timers that drive it.
+///
+/// Messages travel as `Packet::HandshakeInit` and `Packet::HandshakeResponse`
+/// whose header carries the sender's session ID, so each side learns the ID
+/// its peer chose.
+///
+/// The initiator sends the first message and retransmits until the
+/// response arrives, backing off from `retransmit` to `max_retransmit`.
+/// Every retransmission is a new handshake with a fresh ephemeral key, so
+/// a response to an earlier copy simply fails to decrypt and is dropped
+/// without disturbing the current attempt. The first message also carries
+/// a timestamp that the responder's caller checks to refuse replayed or
+/// reordered initiations. Both sides give up after `timeout`.
+///
+/// Both messages carry the sender's `Capabilities` after any timestamp;
+/// `negotiated` gives what the two sides have in common.
 pub struct Handshake {
     session: NoiseSession,
+    initiator: Option<InitiatorKeys>,
+    timers: HandshakeTimers,
+    started: Instant,
+    attempts: u32,
+    retransmit_at: Option<Instant>,
+    timestamp: Option<u64>,
+    local_id: u32,
+    remote_id: Option<u32>,
+    capabilities: Capabilities,
+    peer_capabilities: Option<Capabilities>,
 }
 
 impl Handshake {
-    pub fn new_initiator(privk: &[u8], pubk: &[u8], psk: &[u8]) -> Result<Self, Box<dyn Error>> {
+    pub fn new_initiator(privk: &SecretKey, pubk: &[u8; 32], psk: &SecretKey) -> Result<Self, Box<dyn Error>> {
         Ok(Self {
             session: NoiseSession::new_initiator(privk, pubk, psk)?,
+            initiator: Some(InitiatorKeys { private: privk.clone(), remote: *pubk, psk: psk.clone() }),
+            timers: HandshakeTimers::default(),
+            started: Instant::now(),
+            attempts: 0,
+            retransmit_at: None,
+            timestamp: None,
+            local_id: OsRng.next_u32(),
+            remote_id: None,
+            capabilities: Capabilities::default(),
+            peer_capabilities: None,
         })
     }
 
-    pub fn new_responder(privk: &[u8], pubk: &[u8], psk: &[u8]) -> Result<Self, Box<dyn Error>> {
+    pub fn new_responder(privk: &SecretKey, authorizer: Arc<dyn PeerAuthorizer>) -> Result<Self, Box<dyn Error>> {
         Ok(Self {
-            session: NoiseSession::new_responder(privk, pubk, psk)?,
+            session: NoiseSession::new_responder(privk, authorizer)?,
+            initiator: None,
+            timers: HandshakeTimers::default(),
+            started: Instant::now(),
+            attempts: 0,
+            retransmit_at: None,
+            timestamp: None,
+            local_id: OsRng.next_u32(),
+            remote_id: None,
+            capabilities: Capabilities::default(),
+            peer_capabilities: None,
         })
     }
 
+    pub fn with_timers(mut self, timers: HandshakeTimers) -> Self {
+        self.timers = timers;
+        self
+    }
+
+    /// Advertises `capabilities` instead of the defaults.
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Uses `id` instead of a random session ID, e.g. one the caller has
+    /// checked is not already in use.
+    pub fn with_local_id(mut self, id: u32) -> Self {
+        self.local_id = id;
+        self
+    }
+
+    /// Writes our next message as a framed packet, if it is our turn. On
+    /// the initiator this also arms the retransmission timer.
     pub fn next_outbound(&mut self, out: &mut [u8]) -> Result<usize, Box<dyn Error>> {
         if self.session.is_ready() {
             return Ok(0);
         }
-        Ok(self.session.write_handshake(out)?)
+
+        let mut message = [0u8; 1024];
+        let packet = if self.initiator.is_some() {
+            let mut payload = next_timestamp().to_be_bytes().to_vec();
+            payload.extend_from_slice(&self.capabilities.encode());
+            let n = self.session.write_handshake(&payload, &mut message)?;
+            self.attempts += 1;
+            self.retransmit_at = Some(Instant::now() + self.timers.backoff(self.attempts));
+            Packet::HandshakeInit(HandshakeInit { payload: Bytes::copy_from_slice(&message[..n]) })
+        } else {
+            let n = self.session.write_handshake(&self.capabilities.encode(), &mut message)?;
+            Packet::HandshakeResponse(HandshakeResponse { payload: Bytes::copy_from_slice(&message[..n]) })
+        };
+
+        if self.session.is_ready() {
+            self.retransmit_at = None;
+        }
+
+        let datagram = packet.serialize(self.local_id);
+        out.get_mut(..datagram.len()).ok_or("output buffer too small")?.copy_from_slice(&datagram);
+        Ok(datagram.len())
     }
 
+    /// Feeds a received handshake packet into the handshake. A stale or
+    /// duplicate message is rejected without changing state, so the caller
+    /// can log it and keep waiting for the right one.
     pub fn process_inbound(&mut self, input: &[u8]) -> Result<(), Box<dyn Error>> {
-        let mut tmp = [0u8; 1024];
-        self.session.read_handshake(input, &mut tmp)?;
+        let (packet, sender_id) = Packet::deserialize(input)?;
+        let message = match (packet, self.initiator.is_some()) {
+            (Packet::HandshakeResponse(p), true) => p.payload,
+            (Packet::HandshakeInit(p), false) => p.payload,
+            (other, _) => return Err(format!("unexpected {:?} packet during handshake", other.packet_type()).into()),
+        };
+
+        let payload = self.session.read_handshake(&message)?;
+        self.remote_id = Some(sender_id);
+
+        let capabilities = if self.initiator.is_none() {
+            let ts = payload.get(..TIMESTAMP_LEN).ok_or("initiation carries no timestamp")?;
+            self.timestamp = Some(u64::from_be_bytes(ts.try_into()?));
+            &payload[TIMESTAMP_LEN..]
+        } else {
+            &payload[..]
+        };
+        self.peer_capabilities = Some(Capabilities::decode(capabilities)?);
+        if self.session.is_ready() {
+            self.retransmit_at = None;
+        }
         Ok(())
     }
 
+    /// Runs the timers: returns a retransmission to send (or 0 if none is
+    /// due yet), or an error once the handshake has timed out.
+    pub fn poll(&mut self, now: Instant, out: &mut [u8]) -> Result<usize, Box<dyn Error>> {
+        if self.is_complete() {
+            return Ok(0);
+        }
+        if self.is_expired(now) {
+            return Err(format!(
+                "handshake timed out after {:?} ({} attempt(s))",
+                self.timers.timeout,
+                self.attempts
+            ).into());
+        }
+
+        let Some(keys) = self.initiator.as_ref() else { return Ok(0) };
+        if self.retransmit_at.is_some_and(|at| now >= at) {
+            self.session = NoiseSession::new_initiator(&keys.private, &keys.remote, &keys.psk)?;
+            log::debug!("Retransmitting handshake initiation (attempt {})", self.attempts + 1);
+            return self.next_outbound(out);
+        }
+        Ok(0)
+    }
+
+    /// When `poll` next has something to do.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if self.is_complete() {
+            return None;
+        }
+        let give_up = self.started + self.timers.timeout;
+        Some(self.retransmit_at.map_or(give_up, |at| at.min(give_up)))
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        !self.is_complete() && now >= self.started + self.timers.timeout
+    }
+
     pub fn is_complete(&self) -> bool {
         self.session.is_ready()
     }
 
+    pub fn remote_static(&self) -> Option<&[u8; 32]> {
+        self.session.remote_static()
+    }
+
+    /// The session ID we put in our handshake packets.
+    pub fn local_id(&self) -> u32 {
+        self.local_id
+    }
+
+    /// The session ID the peer put in its handshake packet.
+    pub fn remote_id(&self) -> Option<u32> {
+        self.remote_id
+    }
+
+    /// On the responder, the timestamp the initiator put in its first
+    /// message. Newer initiations from the same peer carry larger values.
+    pub fn initiation_timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    /// What both sides support, once the peer's capabilities are known.
+    /// Fails if they share no protocol version.
+    pub fn negotiated(&self) -> Result<Capabilities, Box<dyn Error>> {
+        let peer = self.peer_capabilities.as_ref().ok_or("peer capabilities not received yet")?;
+        self.capabilities.intersect(peer)
+    }
+
     pub fn into_session(self) -> NoiseSession {
         self.session
     }
 }
+
+/// Nanoseconds since the Unix epoch, forced to increase within this process.
+fn next_timestamp() -> u64 {
+    static LAST: AtomicU64 = AtomicU64::new(0);
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+    let prev = LAST.fetch_max(now, Ordering::Relaxed);
+    if now > prev {
+        now
+    } else {
+        LAST.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
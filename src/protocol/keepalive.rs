@@ -0,0 +1,127 @@
+use crate::protocol::packet::KeepAlive;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// How often to probe an idle peer and how long it may stay silent
+/// before it is considered dead. A zero duration disables that part.
+#[derive(Debug, Clone, Copy)]
+pub struct KeepAlivePolicy {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for KeepAlivePolicy {
+    fn default() -> Self {
+        Self { interval: Duration::from_secs(25), timeout: Duration::from_secs(90) }
+    }
+}
+
+/// Round-trip times measured from keepalive echoes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RttStats {
+    pub latest: Option<Duration>,
+    /// Exponentially weighted average with gain 1/8, as in RFC 6298.
+    pub smoothed: Option<Duration>,
+    /// Mean difference between consecutive samples with gain 1/16, as in
+    /// RFC 3550.
+    pub jitter: Duration,
+    pub min: Option<Duration>,
+    pub samples: u64,
+}
+
+impl RttStats {
+    fn record(&mut self, rtt: Duration) {
+        if let Some(prev) = self.latest {
+            let delta = rtt.abs_diff(prev);
+            self.jitter = if delta > self.jitter {
+                self.jitter + (delta - self.jitter) / 16
+            } else {
+                self.jitter - (self.jitter - delta) / 16
+            };
+        }
+        self.smoothed = Some(match self.smoothed {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+        self.min = Some(self.min.map_or(rtt, |m| m.min(rtt)));
+        self.latest = Some(rtt);
+        self.samples += 1;
+    }
+}
+
+/// Keepalive bookkeeping for one session.
+///
+/// A probe goes out once the session has been quiet in either direction
+/// for `interval`, then at most once per `interval`. Its timestamp comes
+/// from our own clock and is echoed back unchanged, so the peer's clock
+/// never matters. Any authenticated packet proves the peer alive.
+pub(crate) struct Liveness {
+    policy: KeepAlivePolicy,
+    last_sent: Instant,
+    last_received: Instant,
+    last_probe: Option<Instant>,
+    pending: Option<KeepAlive>,
+    rtt: RttStats,
+}
+
+impl Liveness {
+    pub(crate) fn new(policy: KeepAlivePolicy, now: Instant) -> Self {
+        Self { policy, last_sent: now, last_received: now, last_probe: None, pending: None, rtt: RttStats::default() }
+    }
+
+    pub(crate) fn sent(&mut self, now: Instant) {
+        self.last_sent = now;
+    }
+
+    pub(crate) fn received(&mut self, now: Instant) {
+        self.last_received = now;
+    }
+
+    /// A probe to send now, if one is due.
+    pub(crate) fn probe(&mut self, now: Instant) -> Option<KeepAlive> {
+        let interval = self.policy.interval;
+        if interval.is_zero() || self.last_probe.is_some_and(|at| now.duration_since(at) < interval) {
+            return None;
+        }
+        if now.duration_since(self.last_sent) < interval && now.duration_since(self.last_received) < interval {
+            return None;
+        }
+
+        let probe = KeepAlive::new(clock_micros(now));
+        self.last_probe = Some(now);
+        self.pending = Some(probe.clone());
+        Some(probe)
+    }
+
+    /// Takes an echo of our latest probe into the RTT estimate and returns
+    /// the sample. Echoes of older probes are ignored.
+    pub(crate) fn echoed(&mut self, echo: &KeepAlive, now: Instant) -> Option<Duration> {
+        let pending = self.pending.as_ref()?;
+        if pending.random_data != echo.random_data || pending.timestamp != echo.timestamp {
+            return None;
+        }
+        self.pending = None;
+
+        let rtt = Duration::from_micros(clock_micros(now).saturating_sub(echo.timestamp));
+        self.rtt.record(rtt);
+        Some(rtt)
+    }
+
+    pub(crate) fn is_dead(&self, now: Instant) -> bool {
+        !self.policy.timeout.is_zero() && now.duration_since(self.last_received) >= self.policy.timeout
+    }
+
+    pub(crate) fn silence(&self, now: Instant) -> Duration {
+        now.duration_since(self.last_received)
+    }
+
+    pub(crate) fn rtt(&self) -> RttStats {
+        self.rtt
+    }
+}
+
+/// Microseconds on a clock local to this process, for keepalive timestamps.
+fn clock_micros(now: Instant) -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    now.saturating_duration_since(*START.get_or_init(Instant::now)).as_micros() as u64
+}
@@ -1,9 +1,19 @@
 pub mod packet;
 pub mod transport;
 pub mod handshake;
+pub mod replay;
+pub mod control;
+pub mod keepalive;
+pub mod capabilities;
 
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::net::routing::RoutingTable;
+use keepalive::KeepAlivePolicy;
+use transport::RekeyPolicy;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
@@ -18,10 +28,20 @@ pub struct ServerSettings {
     pub listen_addr: String,
     pub private_key: PathBuf,
     pub public_key: Option<PathBuf>,
+    /// Peers file or directory; without one, `PEER_PUBLIC` lines in the
+    /// key file are accepted with its shared `PSK`.
+    #[serde(default)]
+    pub peers: Option<PathBuf>,
     pub max_connections: usize,
     pub session_timeout: u64,
     pub keepalive_interval: u64,
     pub keepalive_timeout: u64,
+    /// File keeping client address leases across restarts.
+    #[serde(default)]
+    pub lease_file: Option<PathBuf>,
+    /// Seconds a client keeps its dynamic address after it was last seen.
+    #[serde(default = "default_lease_time")]
+    pub lease_time: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -42,6 +62,10 @@ pub struct ClientSettings {
     pub auto_reconnect: bool,
     pub reconnect_delay: u64,
     pub max_reconnect_attempts: u64,
+    #[serde(default = "default_keepalive_interval")]
+    pub keepalive_interval: u64,
+    #[serde(default = "default_keepalive_timeout")]
+    pub keepalive_timeout: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -53,6 +77,9 @@ pub struct NetworkSettings {
     pub ip_forwarding: bool,
     #[serde(default)]
     pub dns_servers: Vec<String>,
+    /// Inner source addresses accepted from the other end of the tunnel,
+    /// and destinations sent there: on the server, a limit for every client
+    /// on top of its own allowed IPs. Empty allows any.
     #[serde(default)]
     pub allowed_ips: Vec<String>,
     #[serde(default)]
@@ -90,8 +117,142 @@ pub struct AdvancedSettings {
     pub enable_compression: bool,
     #[serde(default = "default_compression_level")]
     pub compression_level: u32,
+    #[serde(default = "default_rekey_after_time")]
+    pub rekey_after_time: u64,
+    #[serde(default = "default_rekey_after_packets")]
+    pub rekey_after_packets: u64,
+    #[serde(default = "default_rekey_after_bytes")]
+    pub rekey_after_bytes: u64,
+}
+
+impl ServerConfig {
+    /// Reads a TOML config file such as `config/server.toml`.
+    pub fn load(path: &Path) -> crate::Result<Self> {
+        load_toml(path)
+    }
+
+    /// Expands a leading `~` in every path setting.
+    pub fn expand_paths(&mut self) {
+        let server = &mut self.server;
+        server.private_key = expand_home(&server.private_key);
+        for path in [&mut server.public_key, &mut server.peers, &mut server.lease_file, &mut self.logging.file] {
+            *path = path.as_deref().map(expand_home);
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Reads a TOML config file such as `config/client.toml`.
+    pub fn load(path: &Path) -> crate::Result<Self> {
+        load_toml(path)
+    }
+
+    /// Expands a leading `~` in every path setting.
+    pub fn expand_paths(&mut self) {
+        self.client.private_key = expand_home(&self.client.private_key);
+        self.client.server_public_key = expand_home(&self.client.server_public_key);
+        self.logging.file = self.logging.file.as_deref().map(expand_home);
+    }
+}
+
+impl ServerSettings {
+    pub fn keepalive_policy(&self) -> KeepAlivePolicy {
+        KeepAlivePolicy {
+            interval: Duration::from_secs(self.keepalive_interval),
+            timeout: Duration::from_secs(self.keepalive_timeout),
+        }
+    }
+}
+
+impl ClientSettings {
+    pub fn keepalive_policy(&self) -> KeepAlivePolicy {
+        KeepAlivePolicy {
+            interval: Duration::from_secs(self.keepalive_interval),
+            timeout: Duration::from_secs(self.keepalive_timeout),
+        }
+    }
+}
+
+impl NetworkSettings {
+    pub fn allowed_ips(&self) -> crate::Result<RoutingTable<()>> {
+        if self.allowed_ips.is_empty() {
+            return Ok(["0.0.0.0/0", "::/0"].iter().map(|n| (n.parse().unwrap(), ())).collect());
+        }
+        self.allowed_ips.iter().map(|n| Ok((n.parse()?, ()))).collect()
+    }
+}
+
+impl LoggingSettings {
+    /// Starts logging at `level`, to `file` if set. `RUST_LOG` still wins
+    /// over `level`, as with any env_logger program.
+    pub fn init(&self) -> crate::Result<()> {
+        let mut builder = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(&self.level));
+        if let Some(path) = &self.file {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            let file = OpenOptions::new().create(true).append(true).open(path)
+                .map_err(|e| crate::KScopeError::Io(format!("{}: {}", path.display(), e)))?;
+            builder.target(env_logger::Target::Pipe(Box::new(file)));
+        }
+        builder.try_init().map_err(|e| crate::KScopeError::Config(e.to_string()))
+    }
+}
+
+impl AdvancedSettings {
+    pub fn rekey_policy(&self) -> RekeyPolicy {
+        RekeyPolicy {
+            after_time: Duration::from_secs(self.rekey_after_time),
+            after_packets: self.rekey_after_packets,
+            after_bytes: self.rekey_after_bytes,
+            ..RekeyPolicy::default()
+        }
+    }
+}
+
+impl Default for LoggingSettings {
+    fn default() -> Self {
+        Self { level: "info".to_string(), file: None, json_format: false }
+    }
+}
+
+impl Default for AdvancedSettings {
+    fn default() -> Self {
+        Self {
+            congestion_control: default_congestion_control(),
+            init_cwnd: default_init_cwnd(),
+            max_packet_size: default_max_packet_size(),
+            enable_buffering: default_enable_buffering(),
+            buffer_size: default_buffer_size(),
+            enable_pmtud: default_enable_pmtud(),
+            enable_obfuscation: false,
+            obfuscation_mode: String::new(),
+            enable_compression: false,
+            compression_level: default_compression_level(),
+            rekey_after_time: default_rekey_after_time(),
+            rekey_after_packets: default_rekey_after_packets(),
+            rekey_after_bytes: default_rekey_after_bytes(),
+        }
+    }
+}
+
+fn load_toml<T: DeserializeOwned>(path: &Path) -> crate::Result<T> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| crate::KScopeError::Io(format!("{}: {}", path.display(), e)))?;
+    toml::from_str(&text).map_err(|e| crate::KScopeError::Config(format!("{}: {}", path.display(), e)))
+}
+
+/// `path` with a leading `~` replaced by the home directory.
+pub fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    }
 }
 
+fn default_keepalive_interval() -> u64 { 25 }
+fn default_keepalive_timeout() -> u64 { 90 }
+fn default_lease_time() -> u64 { 86400 }
 fn default_congestion_control() -> String { "bbr".to_string() }
 fn default_init_cwnd() -> u32 { 10 }
 fn default_max_packet_size() -> u16 { 1500 }
@@ -99,3 +260,6 @@ fn default_enable_buffering() -> bool { true }
 fn default_buffer_size() -> usize { 1024 }
 fn default_enable_pmtud() -> bool { true }
 fn default_compression_level() -> u32 { 6 }
+fn default_rekey_after_time() -> u64 { 120 }
+fn default_rekey_after_packets() -> u64 { 1 << 30 }
+fn default_rekey_after_bytes() -> u64 { 1 << 36 }
@@ -1,5 +1,15 @@
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use std::convert::TryInto;
+use rand::RngCore;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+/// Version used for handshake and error packets, which every build speaks.
+pub const PROTOCOL_VERSION: u8 = 0x01;
+
+/// Versions this build accepts; transport packets use the one negotiated
+/// in the handshake.
+pub const SUPPORTED_VERSIONS: RangeInclusive<u8> = PROTOCOL_VERSION..=PROTOCOL_VERSION;
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -38,7 +48,12 @@ impl PacketHeader {
     pub const SIZE: usize = 8;
 
     pub fn new(packet_type: PacketType, data_len: u16, session_id: u32) -> Self {
-        Self { version: 0x01, packet_type, data_len, session_id }
+        Self { version: PROTOCOL_VERSION, packet_type, data_len, session_id }
+    }
+
+    pub fn with_version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
     }
 
     pub fn serialize(&self) -> [u8; 8] {
@@ -51,7 +66,16 @@ impl PacketHeader {
     }
 
     pub fn deserialize(d: &[u8]) -> crate::Result<Self> {
-        if d.len() < 8 { return Err(crate::KScopeError::Protocol("Header too small".into())); }
+        if d.len() < Self::SIZE {
+            return Err(crate::KScopeError::Protocol(format!(
+                "Header truncated: {} of {} bytes",
+                d.len(),
+                Self::SIZE
+            )));
+        }
+        if !SUPPORTED_VERSIONS.contains(&d[0]) {
+            return Err(crate::KScopeError::Protocol(format!("Unsupported protocol version: {}", d[0])));
+        }
         Ok(Self {
             version: d[0],
             packet_type: PacketType::try_from(d[1]).map_err(crate::KScopeError::Protocol)?,
@@ -67,13 +91,129 @@ pub struct HandshakeInit { pub payload: Bytes }
 pub struct HandshakeResponse { pub payload: Bytes }
 
 #[derive(Debug, Clone)]
-pub struct TransportData { pub nonce: u64, pub ciphertext: Bytes }
+pub struct TransportData { pub epoch: u32, pub nonce: u64, pub ciphertext: Bytes }
+
+impl TransportData {
+    /// Bytes of epoch and counter ahead of the ciphertext.
+    pub const OVERHEAD: usize = 12;
+
+    /// Decodes `data` as transport data, rejecting any other packet type or
+    /// a malformed packet.
+    pub fn parse(data: &[u8]) -> crate::Result<Self> {
+        match Packet::deserialize(data)?.0 {
+            Packet::TransportData(packet) => Ok(packet),
+            other => Err(crate::KScopeError::Protocol(format!(
+                "Expected transport data, got {:?}",
+                other.packet_type()
+            ))),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct KeepAlive { pub timestamp: u64, pub random_data: [u8; 16] }
 
+impl KeepAlive {
+    /// Timestamp plus random data.
+    pub const SIZE: usize = 24;
+
+    /// A probe stamped with `timestamp` and fresh random data to match its echo by.
+    pub fn new(timestamp: u64) -> Self {
+        let mut random_data = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut random_data);
+        Self { timestamp, random_data }
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut b = [0u8; Self::SIZE];
+        b[..8].copy_from_slice(&self.timestamp.to_be_bytes());
+        b[8..].copy_from_slice(&self.random_data);
+        b
+    }
+
+    pub fn from_bytes(data: &[u8]) -> crate::Result<Self> {
+        let data: &[u8; Self::SIZE] = data.try_into().map_err(|_| {
+            crate::KScopeError::Protocol(format!("Keepalive must be {} bytes, got {}", Self::SIZE, data.len()))
+        })?;
+        let (timestamp, random_data) = data.split_at(8);
+        Ok(Self {
+            timestamp: u64::from_be_bytes(timestamp.try_into().unwrap()),
+            random_data: random_data.try_into().unwrap(),
+        })
+    }
+}
+
+/// Why a peer refused or closed a session, carried by an `ErrorPacket`.
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// A handshake initiation did not authenticate: unknown or disabled
+    /// key, wrong server key or wrong PSK.
+    AuthFailed = 0x0001,
+    /// A transport packet named a session the receiver does not have.
+    SessionUnknown = 0x0002,
+    /// The server has no room for another session.
+    ServerFull = 0x0003,
+    /// The header carried a protocol version the receiver does not speak.
+    VersionUnsupported = 0x0004,
+    /// The sender is going away and has closed its sessions.
+    ShuttingDown = 0x0005,
+}
+
+impl TryFrom<u16> for ErrorCode {
+    type Error = String;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0x0001 => ErrorCode::AuthFailed,
+            0x0002 => ErrorCode::SessionUnknown,
+            0x0003 => ErrorCode::ServerFull,
+            0x0004 => ErrorCode::VersionUnsupported,
+            0x0005 => ErrorCode::ShuttingDown,
+            _ => return Err(format!("Unknown error code: {}", value)),
+        })
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorCode::AuthFailed => "authentication failed",
+            ErrorCode::SessionUnknown => "unknown session",
+            ErrorCode::ServerFull => "server full",
+            ErrorCode::VersionUnsupported => "unsupported protocol version",
+            ErrorCode::ShuttingDown => "shutting down",
+        })
+    }
+}
+
+/// How long a peer must have been silent before an unauthenticated notice
+/// may end its session, so a forged one cannot cut off live traffic.
+pub const NOTICE_GRACE: Duration = Duration::from_secs(5);
+
+/// An error notice: a 2-byte code followed by an optional UTF-8 message.
+///
+/// Error packets are not authenticated. Like transport packets they carry
+/// the receiver's session ID, or, when the sender does not know it, the ID
+/// from the packet being answered.
 #[derive(Debug, Clone)]
-pub struct ErrorPacket { pub code: u16, pub message: String }
+pub struct ErrorPacket { pub code: ErrorCode, pub message: String }
+
+impl ErrorPacket {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
+
+impl fmt::Display for ErrorPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.message.is_empty() {
+            write!(f, "{}", self.code)
+        } else {
+            write!(f, "{}: {}", self.code, self.message)
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum Packet {
@@ -100,15 +240,16 @@ impl Packet {
             Packet::HandshakeInit(p) => p.payload.clone(),
             Packet::HandshakeResponse(p) => p.payload.clone(),
             Packet::TransportData(p) => {
-                let mut b = BytesMut::with_capacity(8 + p.ciphertext.len());
+                let mut b = BytesMut::with_capacity(TransportData::OVERHEAD + p.ciphertext.len());
+                b.put_u32(p.epoch);
                 b.put_u64(p.nonce);
                 b.extend_from_slice(&p.ciphertext);
                 b.freeze()
             }
-            Packet::KeepAlive(_) => Bytes::new(),
+            Packet::KeepAlive(p) => Bytes::copy_from_slice(&p.to_bytes()),
             Packet::Error(p) => {
-                let mut b = BytesMut::new();
-                b.put_u16(p.code);
+                let mut b = BytesMut::with_capacity(2 + p.message.len());
+                b.put_u16(p.code as u16);
                 b.extend_from_slice(p.message.as_bytes());
                 b.freeze()
             }
@@ -121,22 +262,111 @@ impl Packet {
         out.freeze()
     }
 
+    /// Decodes one datagram. Never panics: anything that does not match
+    /// its header is reported as a `KScopeError::Protocol` saying why.
     pub fn deserialize(buf: &[u8]) -> crate::Result<(Packet, u32)> {
         let h = PacketHeader::deserialize(buf)?;
-        let data = &buf[8..8 + h.data_len as usize];
+        let body = &buf[PacketHeader::SIZE..];
+        let data = body.get(..h.data_len as usize).ok_or_else(|| {
+            crate::KScopeError::Protocol(format!(
+                "Packet truncated: header claims {} bytes, {} received",
+                h.data_len,
+                body.len()
+            ))
+        })?;
 
         let pkt = match h.packet_type {
             PacketType::HandshakeInit => Packet::HandshakeInit(HandshakeInit { payload: Bytes::copy_from_slice(data) }),
             PacketType::HandshakeResponse => Packet::HandshakeResponse(HandshakeResponse { payload: Bytes::copy_from_slice(data) }),
             PacketType::TransportData => {
+                if data.len() < TransportData::OVERHEAD {
+                    return Err(crate::KScopeError::Protocol(format!(
+                        "Transport data too short: {} bytes, need at least {}",
+                        data.len(),
+                        TransportData::OVERHEAD
+                    )));
+                }
                 let mut d = Bytes::copy_from_slice(data);
+                let epoch = d.get_u32();
                 let nonce = d.get_u64();
-                Packet::TransportData(TransportData { nonce, ciphertext: d })
+                Packet::TransportData(TransportData { epoch, nonce, ciphertext: d })
+            }
+            PacketType::KeepAlive => Packet::KeepAlive(KeepAlive::from_bytes(data)?),
+            PacketType::ErrorPacket => {
+                let (code, message) = data.split_first_chunk::<2>()
+                    .ok_or_else(|| crate::KScopeError::Protocol("Error packet has no code".into()))?;
+                let code = ErrorCode::try_from(u16::from_be_bytes(*code)).map_err(crate::KScopeError::Protocol)?;
+                let message = std::str::from_utf8(message)
+                    .map_err(|_| crate::KScopeError::Protocol("Error message is not UTF-8".into()))?;
+                Packet::Error(ErrorPacket::new(code, message))
             }
-            PacketType::KeepAlive => Packet::KeepAlive(KeepAlive { timestamp: 0, random_data: [0; 16] }),
-            PacketType::ErrorPacket => Packet::Error(ErrorPacket { code: 0, message: String::new() }),
         };
 
         Ok((pkt, h.session_id))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SESSION: u32 = 0xdead_beef;
+    const CODES: [ErrorCode; 5] = [
+        ErrorCode::AuthFailed,
+        ErrorCode::SessionUnknown,
+        ErrorCode::ServerFull,
+        ErrorCode::VersionUnsupported,
+        ErrorCode::ShuttingDown,
+    ];
+
+    /// A datagram of type `packet_type` carrying `data` as is.
+    fn raw(packet_type: PacketType, data: &[u8]) -> Vec<u8> {
+        let mut datagram = PacketHeader::new(packet_type, data.len() as u16, SESSION).serialize().to_vec();
+        datagram.extend_from_slice(data);
+        datagram
+    }
+
+    #[test]
+    fn keepalive_round_trip() {
+        let probe = KeepAlive::new(1_700_000_000_123);
+        let datagram = Packet::KeepAlive(probe.clone()).serialize(SESSION);
+
+        let (Packet::KeepAlive(decoded), id) = Packet::deserialize(&datagram).unwrap() else {
+            panic!("not a keepalive");
+        };
+        assert_eq!(id, SESSION);
+        assert_eq!(decoded.timestamp, probe.timestamp);
+        assert_eq!(decoded.random_data, probe.random_data);
+    }
+
+    #[test]
+    fn keepalive_truncated() {
+        let datagram = Packet::KeepAlive(KeepAlive::new(1)).serialize(SESSION);
+        assert!(Packet::deserialize(&datagram[..datagram.len() - 1]).is_err());
+        assert!(Packet::deserialize(&raw(PacketType::KeepAlive, &[0; KeepAlive::SIZE - 1])).is_err());
+        assert!(KeepAlive::from_bytes(&[0; KeepAlive::SIZE + 1]).is_err());
+    }
+
+    #[test]
+    fn error_round_trip() {
+        for code in CODES {
+            for message in ["", "going away"] {
+                let datagram = Packet::Error(ErrorPacket::new(code, message)).serialize(SESSION);
+                let (Packet::Error(decoded), id) = Packet::deserialize(&datagram).unwrap() else {
+                    panic!("not an error packet");
+                };
+                assert_eq!((id, decoded.code, decoded.message.as_str()), (SESSION, code, message));
+            }
+        }
+    }
+
+    #[test]
+    fn error_malformed() {
+        let datagram = Packet::Error(ErrorPacket::new(ErrorCode::ServerFull, "full")).serialize(SESSION);
+        assert!(Packet::deserialize(&datagram[..datagram.len() - 1]).is_err());
+        assert!(Packet::deserialize(&raw(PacketType::ErrorPacket, &[0x00])).is_err());
+        assert!(Packet::deserialize(&raw(PacketType::ErrorPacket, &[0x00, 0x00])).is_err());
+        assert!(Packet::deserialize(&raw(PacketType::ErrorPacket, &[0x00, 0x06])).is_err());
+        assert!(Packet::deserialize(&raw(PacketType::ErrorPacket, &[0x00, 0x01, 0xff])).is_err());
+    }
+}
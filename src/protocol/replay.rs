@@ -0,0 +1,83 @@
+/// Total bits tracked by the window, including one word of slack so a word
+/// can be cleared as the window slides (RFC 6479).
+const WINDOW_BITS: u64 = 2048;
+const WORD_BITS: u64 = u64::BITS as u64;
+const WORDS: usize = (WINDOW_BITS / WORD_BITS) as usize;
+
+/// How far behind the highest counter a packet may arrive and still be accepted.
+pub const WINDOW_SIZE: u64 = WINDOW_BITS - WORD_BITS;
+
+/// Counters at or above this are never accepted; the top of the nonce space
+/// is reserved by Noise for rekeying.
+pub const REJECT_AFTER_MESSAGES: u64 = u64::MAX - WINDOW_BITS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayCheck {
+    Fresh,
+    Duplicate,
+    TooOld,
+}
+
+/// WireGuard-style sliding window over received transport counters.
+///
+/// Tolerates reordering and loss within `WINDOW_SIZE` packets while
+/// rejecting any counter that was already seen. `check` is side-effect
+/// free so the window only advances for packets that authenticate.
+pub struct ReplayWindow {
+    bitmap: Box<[u64; WORDS]>,
+    /// One past the highest accepted counter; 0 means nothing seen yet.
+    next: u64,
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self { bitmap: Box::new([0; WORDS]), next: 0 }
+    }
+
+    pub fn check(&self, counter: u64) -> ReplayCheck {
+        if counter >= REJECT_AFTER_MESSAGES {
+            return ReplayCheck::TooOld;
+        }
+        if counter >= self.next {
+            return ReplayCheck::Fresh;
+        }
+        if counter + WINDOW_SIZE < self.next {
+            return ReplayCheck::TooOld;
+        }
+
+        let (word, bit) = Self::position(counter);
+        if self.bitmap[word] & bit != 0 {
+            ReplayCheck::Duplicate
+        } else {
+            ReplayCheck::Fresh
+        }
+    }
+
+    /// Records `counter` as received. Call only after `check` returned
+    /// `Fresh` and the packet decrypted successfully.
+    pub fn update(&mut self, counter: u64) {
+        if counter >= self.next {
+            let current = self.next.saturating_sub(1) / WORD_BITS;
+            let target = counter / WORD_BITS;
+            let stale = (target - current).min(WORDS as u64);
+            for i in 1..=stale {
+                self.bitmap[((current + i) % WORDS as u64) as usize] = 0;
+            }
+            self.next = counter + 1;
+        }
+
+        let (word, bit) = Self::position(counter);
+        self.bitmap[word] |= bit;
+    }
+
+    fn position(counter: u64) -> (usize, u64) {
+        let word = ((counter / WORD_BITS) % WORDS as u64) as usize;
+        (word, 1 << (counter % WORD_BITS))
+    }
+}
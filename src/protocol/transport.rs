@@ -1,21 +1,480 @@
-use crate::crypto::noise::NoiseSession;
+use crate::crypto::cipher::{CipherKey, TAG_LEN};
+use crate::crypto::noise::{NoiseSession, TransportKeys};
+use crate::protocol::capabilities::Capabilities;
+use crate::protocol::control::Control;
+use crate::protocol::keepalive::{KeepAlivePolicy, Liveness, RttStats};
+use crate::protocol::packet::{KeepAlive, PacketHeader, PacketType, TransportData};
+use crate::protocol::replay::{ReplayCheck, ReplayWindow, REJECT_AFTER_MESSAGES};
+use crate::KScopeError;
+use bytes::{BufMut, Bytes, BytesMut};
 use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use zeroize::Zeroize;
 
+/// How many epochs a peer may be ahead of us before its packets are
+/// rejected instead of fast-forwarding our keys to match.
+const MAX_EPOCH_SKIP: u32 = 3;
+
+/// When to rotate transport keys. A zero threshold disables that trigger.
+#[derive(Debug, Clone, Copy)]
+pub struct RekeyPolicy {
+    pub after_time: Duration,
+    pub after_packets: u64,
+    pub after_bytes: u64,
+    /// How long the previous epoch stays valid for in-flight packets. A
+    /// transport raises it to two keepalive intervals, so a peer that
+    /// missed the rotating packet hears the new epoch before the old one
+    /// is dropped.
+    pub overlap: Duration,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        Self {
+            after_time: Duration::from_secs(120),
+            after_packets: 1 << 30,
+            after_bytes: 1 << 36,
+            overlap: Duration::from_secs(50),
+        }
+    }
+}
+
+/// Per-session packet counters, exposed for metrics through `SessionInfo`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TransportStats {
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    /// Packets whose counter was already received.
+    pub rx_replayed: u64,
+    /// Packets too far behind the replay window to be checked.
+    pub rx_too_old: u64,
+    /// Packets that failed AEAD authentication.
+    pub rx_auth_failed: u64,
+    /// Packets for an epoch we no longer (or do not yet) hold keys for.
+    pub rx_bad_epoch: u64,
+    /// Key rotations performed, whether started locally or by the peer.
+    pub rekeys: u64,
+}
+
+/// A snapshot of one established session, for status queries.
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    /// Our receiver index for the session.
+    pub id: u32,
+    /// Static key of the peer.
+    pub peer: Option<[u8; 32]>,
+    /// The peer's current UDP endpoint.
+    pub endpoint: SocketAddr,
+    /// The client's address inside the tunnel.
+    pub tunnel_ip: Option<IpAddr>,
+    pub rtt: RttStats,
+    pub stats: TransportStats,
+}
+
+/// Shared, cloneable view of the sessions a running client or server
+/// holds. The run loop refreshes it about once a second.
+#[derive(Debug, Clone, Default)]
+pub struct SessionView(Arc<Mutex<Vec<SessionInfo>>>);
+
+impl SessionView {
+    pub fn snapshot(&self) -> Vec<SessionInfo> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub(crate) fn update(&self, sessions: Vec<SessionInfo>) {
+        *self.0.lock().unwrap() = sessions;
+    }
+}
+
+/// One generation of transport keys with its own counters and window.
+struct Epoch {
+    id: u32,
+    send: CipherKey,
+    recv: CipherKey,
+    send_counter: u64,
+    window: ReplayWindow,
+    started: Instant,
+    tx_bytes: u64,
+}
+
+impl Epoch {
+    fn new(id: u32, send: CipherKey, recv: CipherKey) -> Self {
+        Self {
+            id,
+            send,
+            recv,
+            send_counter: 0,
+            window: ReplayWindow::new(),
+            started: Instant::now(),
+            tx_bytes: 0,
+        }
+    }
+
+    /// Both directions step through Noise `REKEY` together, so either peer
+    /// can derive epoch `n + 1` from epoch `n` without any extra messages.
+    fn next(&self) -> Self {
+        Self::new(self.id.wrapping_add(1), self.send.rekey(), self.recv.rekey())
+    }
+
+    fn is_due(&self, policy: &RekeyPolicy) -> bool {
+        (!policy.after_time.is_zero() && self.started.elapsed() >= policy.after_time)
+            || (policy.after_packets > 0 && self.send_counter >= policy.after_packets)
+            || (policy.after_bytes > 0 && self.tx_bytes >= policy.after_bytes)
+            || self.send_counter >= REJECT_AFTER_MESSAGES
+    }
+}
+
+/// Transport-phase encryption with explicit per-packet counters and
+/// periodic key rotation.
+///
+/// Every packet carries its key epoch and counter, so packets decrypt
+/// independently of each other; a replay window per epoch rejects
+/// duplicates while tolerating loss and reordering. When the local
+/// `RekeyPolicy` fires, the sender moves to the next epoch and the peer
+/// follows as soon as it authenticates a packet from it. The previous
+/// epoch is kept for `overlap` so packets already in flight still decrypt.
+///
+/// The transport also keeps the session's keepalive state: sealing and
+/// opening packets feed it, and the owner drives it with `poll_keepalive`.
+/// Packets carry the protocol version from the negotiated `Capabilities`.
 pub struct SecureTransport {
-    noise: NoiseSession,
+    current: Epoch,
+    previous: Option<(Epoch, Instant)>,
+    policy: RekeyPolicy,
+    stats: TransportStats,
+    liveness: Liveness,
+    capabilities: Capabilities,
 }
 
 impl SecureTransport {
-    pub fn new(noise: NoiseSession) -> Self {
-        Self { noise }
+    pub fn new(noise: NoiseSession) -> crate::Result<Self> {
+        Self::with_policy(noise, RekeyPolicy::default())
+    }
+
+    pub fn with_policy(noise: NoiseSession, policy: RekeyPolicy) -> crate::Result<Self> {
+        Ok(Self::from_keys(noise.into_transport_keys()?, policy))
+    }
+
+    fn from_keys(keys: TransportKeys, policy: RekeyPolicy) -> Self {
+        Self {
+            current: Epoch::new(0, keys.send, keys.recv),
+            previous: None,
+            policy,
+            stats: TransportStats::default(),
+            liveness: Liveness::new(KeepAlivePolicy::default(), Instant::now()),
+            capabilities: Capabilities::default(),
+        }
+    }
+
+    /// Uses what the handshake negotiated. Without `Capabilities::KEEPALIVE`
+    /// no probes are sent and the peer is never declared dead, since an idle
+    /// peer would have no reason to send anything.
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    pub fn with_keepalive(mut self, policy: KeepAlivePolicy) -> Self {
+        self.policy.overlap = self.policy.overlap.max(policy.interval * 2);
+        self.liveness = Liveness::new(policy, Instant::now());
+        self
+    }
+
+    /// Encrypts `plain` under the next send counter, rotating keys first if
+    /// the policy says so, and binds `aad` to the ciphertext. Returns the
+    /// epoch, counter and ciphertext length.
+    pub fn encrypt(&mut self, plain: &[u8], aad: &[u8], out: &mut [u8]) -> Result<(u32, u64, usize), Box<dyn Error>> {
+        if self.current.is_due(&self.policy) {
+            let next = self.current.next();
+            self.install(next);
+        }
+
+        let epoch = &mut self.current;
+        let nonce = epoch.send_counter;
+        let len = epoch.send.encrypt(nonce, aad, plain, out)?;
+        epoch.send_counter += 1;
+        epoch.tx_bytes += plain.len() as u64;
+
+        self.stats.tx_packets += 1;
+        self.stats.tx_bytes += plain.len() as u64;
+        Ok((epoch.id, nonce, len))
+    }
+
+    /// Decrypts a packet sent under `epoch` with counter `nonce`, rejecting
+    /// replays, counters that fell out of the window, unknown epochs and
+    /// any change to `aad`.
+    pub fn decrypt(
+        &mut self,
+        epoch: u32,
+        nonce: u64,
+        aad: &[u8],
+        cipher: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, Box<dyn Error>> {
+        self.expire_previous();
+
+        let ahead = epoch.wrapping_sub(self.current.id);
+        if ahead == 0 {
+            return Self::decrypt_in(&mut self.current, &mut self.stats, nonce, aad, cipher, out);
+        }
+        if let Some((prev, _)) = self.previous.as_mut().filter(|(p, _)| p.id == epoch) {
+            return Self::decrypt_in(prev, &mut self.stats, nonce, aad, cipher, out);
+        }
+        if ahead > MAX_EPOCH_SKIP {
+            self.stats.rx_bad_epoch += 1;
+            return Err(format!("packet for unknown key epoch {}", epoch).into());
+        }
+
+        // The peer rotated first. Only follow once the packet authenticates,
+        // so a forged epoch cannot desynchronise our keys.
+        let mut next = self.current.next();
+        while next.id != epoch {
+            next = next.next();
+        }
+        let len = Self::decrypt_in(&mut next, &mut self.stats, nonce, aad, cipher, out)?;
+        self.install(next);
+        Ok(len)
+    }
+
+    /// Encrypts `plain` and frames it as a serialized `TransportData` packet.
+    /// The packet header is authenticated as associated data.
+    pub fn seal(&mut self, plain: &[u8], session_id: u32) -> crate::Result<Bytes> {
+        let data_len = u16::try_from(TransportData::OVERHEAD + plain.len() + TAG_LEN)
+            .map_err(|_| KScopeError::Protocol("packet too large".into()))?;
+        let header = PacketHeader::new(PacketType::TransportData, data_len, session_id)
+            .with_version(self.capabilities.version())
+            .serialize();
+
+        let mut encrypted = vec![0u8; plain.len() + TAG_LEN];
+        let (epoch, nonce, len) = self.encrypt(plain, &header, &mut encrypted)?;
+
+        let mut datagram = BytesMut::with_capacity(PacketHeader::SIZE + data_len as usize);
+        datagram.extend_from_slice(&header);
+        datagram.put_u32(epoch);
+        datagram.put_u64(nonce);
+        datagram.extend_from_slice(&encrypted[..len]);
+        self.liveness.sent(Instant::now());
+        Ok(datagram.freeze())
+    }
+
+    /// Decrypts a received `TransportData` packet back into the inner IP
+    /// packet, checking that `header` is the one it was sent with.
+    pub fn open(&mut self, header: &PacketHeader, packet: &TransportData) -> crate::Result<Vec<u8>> {
+        if packet.ciphertext.len() < TAG_LEN {
+            return Err(KScopeError::Protocol("transport packet shorter than tag".into()));
+        }
+        if header.version != self.capabilities.version() {
+            return Err(KScopeError::Protocol(format!(
+                "packet uses protocol version {}, session negotiated {}",
+                header.version,
+                self.capabilities.version()
+            )));
+        }
+
+        let mut plain = vec![0u8; packet.ciphertext.len()];
+        let len = self.decrypt(packet.epoch, packet.nonce, &header.serialize(), &packet.ciphertext, &mut plain)?;
+        plain.truncate(len);
+        self.liveness.received(Instant::now());
+        Ok(plain)
+    }
+
+    /// A sealed keepalive probe to send, if the session has been idle long
+    /// enough to need one.
+    pub fn poll_keepalive(&mut self, now: Instant, session_id: u32) -> crate::Result<Option<Bytes>> {
+        if !self.capabilities.has(Capabilities::KEEPALIVE) {
+            return Ok(None);
+        }
+        match self.liveness.probe(now) {
+            Some(probe) => self.seal(&Control::KeepAlive(probe).encode(), session_id).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Feeds the peer's echo of our probe into the RTT estimate and returns
+    /// the new sample, or `None` for an echo of an outdated probe.
+    pub fn keepalive_echoed(&mut self, echo: &KeepAlive, now: Instant) -> Option<Duration> {
+        self.liveness.echoed(echo, now)
+    }
+
+    /// Whether nothing has authenticated from the peer for the keepalive
+    /// timeout.
+    pub fn is_peer_dead(&self, now: Instant) -> bool {
+        self.capabilities.has(Capabilities::KEEPALIVE) && self.liveness.is_dead(now)
+    }
+
+    /// Time since the last packet from the peer authenticated.
+    pub fn silence(&self, now: Instant) -> Duration {
+        self.liveness.silence(now)
+    }
+
+    pub fn rtt(&self) -> RttStats {
+        self.liveness.rtt()
+    }
+
+    /// What the handshake negotiated for this session.
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Current key epoch, starting at 0 after the handshake.
+    pub fn epoch(&self) -> u32 {
+        self.current.id
+    }
+
+    pub fn stats(&self) -> TransportStats {
+        self.stats
+    }
+
+    fn decrypt_in(
+        epoch: &mut Epoch,
+        stats: &mut TransportStats,
+        nonce: u64,
+        aad: &[u8],
+        cipher: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, Box<dyn Error>> {
+        match epoch.window.check(nonce) {
+            ReplayCheck::Fresh => {}
+            ReplayCheck::Duplicate => {
+                stats.rx_replayed += 1;
+                return Err(format!("replayed packet (nonce {})", nonce).into());
+            }
+            ReplayCheck::TooOld => {
+                stats.rx_too_old += 1;
+                return Err(format!("packet outside replay window (nonce {})", nonce).into());
+            }
+        }
+
+        let len = match epoch.recv.decrypt(nonce, aad, cipher, out) {
+            Ok(len) => len,
+            Err(e) => {
+                stats.rx_auth_failed += 1;
+                return Err(e);
+            }
+        };
+        epoch.window.update(nonce);
+        stats.rx_packets += 1;
+        stats.rx_bytes += len as u64;
+        Ok(len)
+    }
+
+    fn install(&mut self, next: Epoch) {
+        log::debug!("Rotating transport keys to epoch {}", next.id);
+        let old = std::mem::replace(&mut self.current, next);
+        self.previous = Some((old, Instant::now() + self.policy.overlap));
+        self.stats.rekeys += 1;
     }
 
-    pub fn encrypt(&mut self, plain: &[u8], out: &mut [u8]) -> Result<usize, Box<dyn Error>> {
-        Ok(self.noise.encrypt(plain, out)?)
+    fn expire_previous(&mut self) {
+        if self.previous.as_ref().is_some_and(|(_, until)| Instant::now() >= *until) {
+            self.previous = None;
+        }
     }
+}
 
-    pub fn decrypt(&mut self, cipher: &[u8], out: &mut [u8]) -> Result<usize, Box<dyn Error>> {
-        Ok(self.noise.decrypt(cipher, out)?)
+impl Zeroize for SecureTransport {
+    /// Wipes the keys of every epoch held. The transport must not be used
+    /// afterwards.
+    fn zeroize(&mut self) {
+        self.current.send.zeroize();
+        self.current.recv.zeroize();
+        if let Some((mut previous, _)) = self.previous.take() {
+            previous.send.zeroize();
+            previous.recv.zeroize();
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::replay::WINDOW_SIZE;
+
+    const SESSION: u32 = 0x1234_5678;
+
+    /// Two ends of one session, with mirrored keys.
+    fn pair(policy: RekeyPolicy) -> (SecureTransport, SecureTransport) {
+        let keys = |send: u8, recv: u8| TransportKeys {
+            send: CipherKey::new(&mut [send; 32]),
+            recv: CipherKey::new(&mut [recv; 32]),
+        };
+        (SecureTransport::from_keys(keys(1, 2), policy), SecureTransport::from_keys(keys(2, 1), policy))
+    }
+
+    fn open(transport: &mut SecureTransport, datagram: &[u8]) -> crate::Result<Vec<u8>> {
+        let header = PacketHeader::deserialize(datagram)?;
+        transport.open(&header, &TransportData::parse(datagram)?)
+    }
+
+    #[test]
+    fn replay_window() {
+        let (mut a, mut b) = pair(RekeyPolicy::default());
+        let sent: Vec<Bytes> = (0..4u8).map(|i| a.seal(&[i], SESSION).unwrap()).collect();
+
+        // In order, then out of order within the window.
+        assert_eq!(open(&mut b, &sent[0]).unwrap(), [0]);
+        assert_eq!(open(&mut b, &sent[2]).unwrap(), [2]);
+        assert_eq!(open(&mut b, &sent[1]).unwrap(), [1]);
+
+        assert!(open(&mut b, &sent[2]).is_err());
+        assert_eq!(b.stats().rx_replayed, 1);
+
+        // A counter far ahead is accepted and slides the window past the
+        // ones never received.
+        a.current.send_counter = WINDOW_SIZE + 100;
+        let ahead = a.seal(&[9], SESSION).unwrap();
+        assert_eq!(open(&mut b, &ahead).unwrap(), [9]);
+        assert!(open(&mut b, &ahead).is_err());
+        assert!(open(&mut b, &sent[3]).is_err());
+
+        let stats = b.stats();
+        assert_eq!((stats.rx_packets, stats.rx_replayed, stats.rx_too_old), (4, 2, 1));
+    }
+
+    #[test]
+    fn tampered_header() {
+        let (mut a, mut b) = pair(RekeyPolicy::default());
+        let datagram = a.seal(b"payload", SESSION).unwrap();
+
+        let mut forged = datagram.to_vec();
+        forged[4..8].copy_from_slice(&(SESSION ^ 1).to_be_bytes());
+        assert!(open(&mut b, &forged).is_err());
+        assert_eq!(b.stats().rx_auth_failed, 1);
+
+        // The failure did not advance the window.
+        assert_eq!(open(&mut b, &datagram).unwrap(), b"payload");
+    }
+
+    #[test]
+    fn rekey_overlap() {
+        let policy = RekeyPolicy { after_packets: 2, ..RekeyPolicy::default() };
+        let (mut a, mut b) = pair(policy);
+        let sent: Vec<Bytes> = (0..3u8).map(|i| a.seal(&[i], SESSION).unwrap()).collect();
+        assert_eq!(a.epoch(), 1);
+
+        // The first packet of the new epoch arrives before older ones.
+        assert_eq!(open(&mut b, &sent[2]).unwrap(), [2]);
+        assert_eq!(b.epoch(), 1);
+        let (_, until) = b.previous.as_ref().unwrap();
+        assert!(*until > Instant::now() + policy.overlap - Duration::from_secs(1));
+        assert_eq!(open(&mut b, &sent[0]).unwrap(), [0]);
+
+        // Once the overlap is over, the previous epoch is gone.
+        b.previous.as_mut().unwrap().1 = Instant::now();
+        assert!(open(&mut b, &sent[1]).is_err());
+        assert!(b.previous.is_none());
+        assert_eq!(b.stats().rx_bad_epoch, 1);
+    }
+
+    #[test]
+    fn overlap_covers_keepalive_interval() {
+        let policy = RekeyPolicy { overlap: Duration::from_secs(15), ..RekeyPolicy::default() };
+        let keepalive = KeepAlivePolicy { interval: Duration::from_secs(40), timeout: Duration::from_secs(120) };
+        let (a, _) = pair(policy);
+        assert_eq!(a.with_keepalive(keepalive).policy.overlap, Duration::from_secs(80));
+    }
+}
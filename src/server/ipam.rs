@@ -0,0 +1,256 @@
+use crate::net::cidr::IpCidr;
+use crate::net::routing::RoutingTable;
+use crate::{KScopeError, Result};
+use base64::{engine::general_purpose, Engine};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A dynamically assigned address and when it may go to another peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lease {
+    pub address: IpAddr,
+    /// Unix time in seconds.
+    pub expires: u64,
+}
+
+/// On-disk form of a lease:
+///
+/// ```toml
+/// [[lease]]
+/// public_key = "base64..."
+/// address = "10.0.0.7"
+/// expires = 1767225600
+/// ```
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LeaseRecord {
+    public_key: String,
+    address: IpAddr,
+    expires: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LeaseFile {
+    #[serde(default)]
+    lease: Vec<LeaseRecord>,
+}
+
+/// Client addresses carved out of the server's tunnel network.
+///
+/// A peer with a static reservation always gets that address. Any other
+/// peer gets a dynamic lease, renewed while its session lasts and kept
+/// until it expires, so a peer that reconnects gets the same address back.
+/// Expired leases are only reclaimed once the network has no never-used
+/// address left. Networks registered to particular peers are never leased.
+/// With a state file, leases survive server restarts.
+#[derive(Debug)]
+pub struct AddressPool {
+    network: IpCidr,
+    lease_time: Duration,
+    state_file: Option<PathBuf>,
+    reserved: HashMap<[u8; 32], IpAddr>,
+    leases: HashMap<[u8; 32], Lease>,
+    /// Networks that belong to particular peers, never leased out.
+    excluded: RoutingTable<()>,
+}
+
+impl AddressPool {
+    /// A pool of the addresses in `tunnel` other than the server's own.
+    pub fn new(tunnel: IpCidr, lease_time: Duration) -> Self {
+        Self {
+            network: tunnel,
+            lease_time,
+            state_file: None,
+            reserved: HashMap::new(),
+            leases: HashMap::new(),
+            excluded: RoutingTable::new(),
+        }
+    }
+
+    /// Keeps `network` out of dynamic leases. Call before loading leases,
+    /// so that ones inside it are dropped.
+    pub fn exclude(&mut self, network: IpCidr) {
+        self.excluded.insert(network, ());
+    }
+
+    /// Loads leases from `path`, if it exists, and saves them there from
+    /// now on. Leases that no longer fit the network are dropped.
+    pub fn with_state_file(mut self, path: &Path) -> Result<Self> {
+        if path.exists() {
+            let text = fs::read_to_string(path)?;
+            let file: LeaseFile = toml::from_str(&text)
+                .map_err(|e| KScopeError::Config(format!("{}: {}", path.display(), e)))?;
+
+            for record in file.lease {
+                let key = general_purpose::STANDARD.decode(&record.public_key).ok()
+                    .and_then(|k| <[u8; 32]>::try_from(k).ok())
+                    .ok_or_else(|| KScopeError::Config(format!("{}: invalid public_key in lease", path.display())))?;
+                if !self.is_assignable(&record.address)
+                    || self.excluded.contains(&record.address)
+                    || self.holder(&record.address).is_some()
+                {
+                    log::warn!("Dropping stale lease of {}", record.address);
+                    continue;
+                }
+                self.leases.insert(key, Lease { address: record.address, expires: record.expires });
+            }
+            log::info!("Loaded {} lease(s) from {}", self.leases.len(), path.display());
+        }
+        self.state_file = Some(path.to_path_buf());
+        Ok(self)
+    }
+
+    /// Reserves `address` for `peer` alone, displacing any dynamic lease
+    /// another peer held on it.
+    pub fn reserve(&mut self, peer: [u8; 32], address: IpAddr) -> Result<()> {
+        if !self.is_assignable(&address) {
+            return Err(KScopeError::Config(format!("{} is not a client address in {}", address, self.network)));
+        }
+        if self.reserved.iter().any(|(k, a)| *a == address && *k != peer) {
+            return Err(KScopeError::Config(format!("{} is reserved twice", address)));
+        }
+        self.reserved.insert(peer, address);
+        let holder = self.holder(&address).copied();
+        let displaced = self.leases.remove(&peer).is_some() | holder.is_some_and(|k| self.leases.remove(&k).is_some());
+        if displaced {
+            self.save();
+        }
+        Ok(())
+    }
+
+    /// The network's prefix, which clients configure with their address.
+    pub fn prefix(&self) -> u8 {
+        self.network.prefix()
+    }
+
+    /// The address for `peer`: its reservation, its current lease renewed,
+    /// or a new lease. `None` if the pool is exhausted.
+    pub fn assign(&mut self, peer: &[u8; 32]) -> Option<IpAddr> {
+        if let Some(address) = self.reserved.get(peer) {
+            return Some(*address);
+        }
+
+        let expires = unix_now() + self.lease_time.as_secs();
+        if let Some(lease) = self.leases.get_mut(peer) {
+            lease.expires = expires;
+            let address = lease.address;
+            self.save();
+            return Some(address);
+        }
+
+        let address = match self.unused() {
+            Some(address) => address,
+            None => {
+                let (holder, lease) = self.leases.iter()
+                    .filter(|(_, l)| l.expires <= unix_now())
+                    .min_by_key(|(_, l)| l.expires)
+                    .map(|(k, l)| (*k, *l))?;
+                self.leases.remove(&holder);
+                lease.address
+            }
+        };
+        self.leases.insert(*peer, Lease { address, expires });
+        self.save();
+        Some(address)
+    }
+
+    /// Extends `peer`'s lease once half of it has run out.
+    pub fn renew(&mut self, peer: &[u8; 32]) {
+        let now = unix_now();
+        let Some(lease) = self.leases.get_mut(peer) else { return };
+        if lease.expires.saturating_sub(now) >= self.lease_time.as_secs() / 2 {
+            return;
+        }
+        lease.expires = now + self.lease_time.as_secs();
+        self.save();
+    }
+
+    /// The first address neither reserved, leased, excluded, nor the
+    /// server's.
+    fn unused(&self) -> Option<IpAddr> {
+        let taken: HashSet<IpAddr> = self.reserved.values()
+            .chain(self.leases.values().map(|l| &l.address))
+            .copied()
+            .collect();
+        hosts(&self.network)
+            .find(|a| *a != self.network.addr() && !taken.contains(a) && !self.excluded.contains(a))
+    }
+
+    fn holder(&self, address: &IpAddr) -> Option<&[u8; 32]> {
+        self.leases.iter().find(|(_, l)| l.address == *address).map(|(k, _)| k)
+    }
+
+    fn is_assignable(&self, address: &IpAddr) -> bool {
+        *address != self.network.addr() && self.network.contains(address) && {
+            let (base, size) = span(&self.network);
+            (1..size.saturating_sub(1)).contains(&(to_u128(address) - base))
+        }
+    }
+
+    /// Writes all leases to the state file. A failure is only logged: the
+    /// leases stay valid in memory.
+    fn save(&self) {
+        let Some(path) = &self.state_file else { return };
+        let mut lease: Vec<_> = self.leases.iter()
+            .map(|(key, lease)| LeaseRecord {
+                public_key: general_purpose::STANDARD.encode(key),
+                address: lease.address,
+                expires: lease.expires,
+            })
+            .collect();
+        lease.sort_by_key(|l| l.address);
+        let file = LeaseFile { lease };
+        let result = toml::to_string(&file)
+            .map_err(|e| KScopeError::Config(e.to_string()))
+            .and_then(|text| {
+                // Write then rename, so a crash never leaves half a file.
+                let tmp = path.with_extension("tmp");
+                fs::write(&tmp, text)?;
+                fs::rename(&tmp, path)?;
+                Ok(())
+            });
+        if let Err(e) = result {
+            log::warn!("Could not save leases to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Host addresses of `network` in order, without its first and last
+/// addresses: the network and broadcast addresses for IPv4, the subnet
+/// router anycast and highest address for IPv6.
+fn hosts(network: &IpCidr) -> impl Iterator<Item = IpAddr> {
+    let (base, size) = span(network);
+    let v4 = network.addr().is_ipv4();
+
+    (1..size.saturating_sub(1)).map(move |i| {
+        if v4 {
+            IpAddr::V4(Ipv4Addr::from((base + i) as u32))
+        } else {
+            IpAddr::V6(Ipv6Addr::from(base + i))
+        }
+    })
+}
+
+/// First address and number of addresses of `network`, as integers.
+fn span(network: &IpCidr) -> (u128, u128) {
+    let bits = if network.addr().is_ipv4() { 32 } else { 128 };
+    let host_bits = bits - network.prefix() as u32;
+    let value = to_u128(&network.addr());
+    let base = value.checked_shr(host_bits).and_then(|v| v.checked_shl(host_bits)).unwrap_or(0);
+    (base, 1u128.checked_shl(host_bits).unwrap_or(u128::MAX))
+}
+
+fn to_u128(address: &IpAddr) -> u128 {
+    match address {
+        IpAddr::V4(a) => u32::from(*a) as u128,
+        IpAddr::V6(a) => u128::from(*a),
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
@@ -1,20 +1,525 @@
+pub mod registry;
 pub mod session;
+pub mod ipam;
+pub mod stats;
 
+use crate::crypto::keyfile::{load_keys, LoadedKeys};
+use crate::protocol::capabilities::Capabilities;
+use crate::net::cidr::IpCidr;
+use crate::net::routing::RoutingTable;
+use crate::protocol::control::{Control, TunnelConfig};
+use crate::protocol::handshake::Handshake;
+use crate::protocol::packet::{ErrorCode, ErrorPacket, Packet, PacketHeader, PacketType, TransportData, NOTICE_GRACE, SUPPORTED_VERSIONS};
+use crate::protocol::transport::{SecureTransport, SessionView};
 use crate::protocol::ServerConfig;
-use crate::Result;
+use crate::tun::{self, TunConfig, TunDevice};
+use crate::{KScopeError, Result};
+use ipam::AddressPool;
+use registry::PeerRegistry;
+use session::{Session, SessionTable};
+use stats::ServerStats;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+const MAX_DATAGRAM: usize = 65535;
 
 pub struct KScopeServer {
     config: ServerConfig,
+    keys: LoadedKeys,
+    registry: Arc<PeerRegistry>,
+    /// Client tunnel addresses; only touched between awaits.
+    pool: Mutex<AddressPool>,
+    /// Which peer owns each inner address: registered allowed IPs plus
+    /// assigned addresses.
+    allowed_ips: Mutex<RoutingTable<[u8; 32]>>,
+    /// Inner addresses any client may use at all.
+    client_networks: RoutingTable<()>,
+    /// DNS servers and routes pushed to every client.
+    dns: Vec<IpAddr>,
+    routes: Vec<IpCidr>,
+    stats: Arc<ServerStats>,
+    view: SessionView,
 }
 
 impl KScopeServer {
     pub async fn new(config: ServerConfig) -> Result<Self> {
-        Ok(Self { config })
+        let keys = load_keys(&config.server.private_key)?;
+
+        let registry = match &config.server.peers {
+            Some(path) => PeerRegistry::load(path)?,
+            None => PeerRegistry::from_keys(&keys)?,
+        };
+        log::info!("{} peer(s) registered", registry.len());
+
+        let net = &config.network;
+        let tunnel: IpCidr = net.tun_ip.parse()?;
+        let allowed_ips = registry.allowed_ips()?;
+        let client_networks = net.allowed_ips()?;
+        let mut pool = AddressPool::new(tunnel, Duration::from_secs(config.server.lease_time));
+        // What a peer registered is its own, whatever the prefix.
+        for network in registry.iter().flat_map(|p| &p.allowed_ips) {
+            pool.exclude(*network);
+        }
+        if let Some(path) = &config.server.lease_file {
+            pool = pool.with_state_file(path)?;
+        }
+        // A peer's single-address allowed IP inside the tunnel is its reservation.
+        for peer in registry.iter() {
+            if let Some(ip) = peer.allowed_ips.iter().find(|c| c.is_host() && tunnel.contains(&c.addr())) {
+                pool.reserve(peer.public_key, ip.addr())
+                    .map_err(|e| KScopeError::Config(format!("peer {}: {}", peer.name, e)))?;
+            }
+        }
+        let dns = net.dns_servers.iter()
+            .map(|s| s.parse().map_err(|_| KScopeError::Config(format!("invalid DNS server: {}", s))))
+            .collect::<Result<_>>()?;
+        let routes = net.routes.iter().map(|r| r.parse()).collect::<Result<_>>()?;
+
+        Ok(Self {
+            config,
+            keys,
+            registry: Arc::new(registry),
+            pool: Mutex::new(pool),
+            allowed_ips: Mutex::new(allowed_ips),
+            client_networks,
+            dns,
+            routes,
+            stats: Arc::default(),
+            view: SessionView::default(),
+        })
     }
-    
+
+    /// Rejection and eviction counters, updated while `run` goes on.
+    pub fn stats(&self) -> Arc<ServerStats> {
+        self.stats.clone()
+    }
+
+    /// The established sessions, refreshed by `run` once a second.
+    pub fn sessions(&self) -> SessionView {
+        self.view.clone()
+    }
+
+    /// Runs the data plane until Ctrl-C: one UDP socket shared by all
+    /// peers, one TUN device, and a session table routing between them.
     pub async fn run(&mut self) -> Result<()> {
-        println!("KScope server starting with config: {:?}", self.config);
-        // TODO: Implement server logic
+        let net = &self.config.network;
+        let tun = Arc::new(TunDevice::create(TunConfig::from_cidr(&net.tun_name, &net.tun_ip, net.mtu)?)?);
+        let mut tun_rx = tun::spawn_reader(tun.clone(), self.config.advanced.buffer_size);
+
+        let socket = UdpSocket::bind(&self.config.server.listen_addr).await?;
+        log::info!("KScope server listening on {}", socket.local_addr()?);
+
+        let mut sessions = SessionTable::new();
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+
+        loop {
+            tokio::select! {
+                res = socket.recv_from(&mut buf) => {
+                    let (n, addr) = res?;
+                    if let Err(e) = self.handle_datagram(&socket, &tun, &mut sessions, addr, &buf[..n]).await {
+                        log::warn!("Dropping datagram from {}: {}", addr, e);
+                    }
+                }
+                packet = tun_rx.recv() => {
+                    let Some(packet) = packet else {
+                        return Err(KScopeError::Io("TUN reader stopped".into()));
+                    };
+                    if let Err(e) = self.handle_tun_packet(&socket, &mut sessions, &packet).await {
+                        log::warn!("Dropping TUN packet: {}", e);
+                    }
+                }
+                _ = ticker.tick() => self.tick(&socket, &mut sessions).await,
+                _ = tokio::signal::ctrl_c() => {
+                    log::info!("Shutting down, {} session(s) open; {}", sessions.len(), self.stats);
+                    notify_shutdown(&socket, &sessions).await;
+                    self.view.update(Vec::new());
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    async fn handle_datagram(
+        &self,
+        socket: &UdpSocket,
+        tun: &TunDevice,
+        sessions: &mut SessionTable,
+        addr: SocketAddr,
+        data: &[u8],
+    ) -> Result<()> {
+        let header = match PacketHeader::deserialize(data) {
+            Ok(header) => header,
+            Err(e) => {
+                // Tell a peer speaking another version, echoing its session ID.
+                if let Some(id) = data.get(4..8).filter(|_| !SUPPORTED_VERSIONS.contains(&data[0])) {
+                    let id = u32::from_be_bytes(id.try_into().unwrap());
+                    send_error(socket, addr, id, ErrorCode::VersionUnsupported, data.len()).await;
+                }
+                return Err(e);
+            }
+        };
+
+        match header.packet_type {
+            PacketType::HandshakeInit => self.handle_handshake(socket, sessions, addr, &header, data).await,
+            PacketType::TransportData => {
+                let packet = TransportData::parse(data)?;
+                if sessions.get_mut(header.session_id).is_none() {
+                    send_error(socket, addr, header.session_id, ErrorCode::SessionUnknown, data.len()).await;
+                    return Err(KScopeError::Protocol(format!("unknown session {:08x}", header.session_id)));
+                }
+                self.handle_transport(socket, tun, sessions, addr, &header, packet).await
+            }
+            PacketType::ErrorPacket => {
+                let (Packet::Error(notice), id) = Packet::deserialize(data)? else { return Ok(()) };
+                self.handle_error(sessions, addr, id, notice)
+            }
+            other => {
+                log::debug!("Ignoring {:?} packet from {}", other, addr);
+                Ok(())
+            }
+        }
+    }
+
+    async fn handle_handshake(
+        &self,
+        socket: &UdpSocket,
+        sessions: &mut SessionTable,
+        addr: SocketAddr,
+        header: &PacketHeader,
+        data: &[u8],
+    ) -> Result<()> {
+        let mut hs = Handshake::new_responder(&self.keys.private, self.registry.clone())?
+            .with_local_id(sessions.allocate_id())
+            .with_capabilities(Capabilities::with_mtu(self.config.network.mtu));
+
+        if let Err(e) = hs.process_inbound(data) {
+            self.stats.rejected_auth.fetch_add(1, Ordering::Relaxed);
+            send_error(socket, addr, header.session_id, ErrorCode::AuthFailed, data.len()).await;
+            return Err(e.into());
+        }
+
+        if let (Some(peer), Some(ts)) = (hs.remote_static(), hs.initiation_timestamp()) {
+            if !sessions.accept_initiation(*peer, ts) {
+                return Err(KScopeError::Protocol("stale or replayed handshake initiation".into()));
+            }
+        }
+
+        // A peer that already has a session may always replace it.
+        let peer = hs.remote_static().copied();
+        let replacing = peer.is_some_and(|p| sessions.by_peer(&p).is_some());
+        let max = self.config.server.max_connections;
+        if max > 0 && sessions.len() >= max && !replacing {
+            self.stats.rejected_full.fetch_add(1, Ordering::Relaxed);
+            send_error(socket, addr, header.session_id, ErrorCode::ServerFull, data.len()).await;
+            return Err(KScopeError::Protocol(format!("server full ({} sessions)", sessions.len())));
+        }
+
+        let negotiated = match hs.negotiated() {
+            Ok(negotiated) => negotiated,
+            Err(e) => {
+                self.stats.rejected_version.fetch_add(1, Ordering::Relaxed);
+                send_error(socket, addr, header.session_id, ErrorCode::VersionUnsupported, data.len()).await;
+                return Err(e.into());
+            }
+        };
+
+        let mut out = [0u8; 1024];
+        let n = hs.next_outbound(&mut out)?;
+        if n > 0 {
+            socket.send_to(&out[..n], addr).await?;
+        }
+
+        let (id, remote_id) = (hs.local_id(), hs.remote_id().unwrap_or_default());
+        // An established peer may re-handshake, e.g. after a client restart;
+        // its old session is replaced only now that the new one is accepted.
+        if replacing {
+            log::info!("{} is re-handshaking", addr);
+        }
+
+        // The responder's IK message finishes the handshake, so there is no
+        // half-open state to keep.
+        if !hs.is_complete() {
+            return Err(KScopeError::Protocol("handshake incomplete after the response".into()));
+        }
+        let name = self.peer_name(peer);
+        log::info!("Handshake complete with {} as {} (session {:08x})", addr, name, id);
+        log::debug!("Session {:08x} negotiated {:?}", id, negotiated);
+        let transport = SecureTransport::with_policy(hs.into_session(), self.config.advanced.rekey_policy())?
+            .with_keepalive(self.config.server.keepalive_policy())
+            .with_capabilities(negotiated);
+
+        let mut session = Session::new(id, remote_id, addr, transport);
+        session.peer = peer;
+        if let Some(peer) = peer {
+            session.tunnel_ip = self.pool.lock().unwrap().assign(&peer);
+            match session.tunnel_ip {
+                Some(ip) => {
+                    log::info!("Assigned {} to {}", ip, self.peer_name(Some(peer)));
+                    self.allowed_ips.lock().unwrap().insert(IpCidr::host(ip), peer);
+                }
+                None => log::warn!("Address pool exhausted, {} gets no address", self.peer_name(Some(peer))),
+            }
+        }
+        self.push_config(socket, &mut session).await?;
+        sessions.insert(session);
+        log::debug!("{} session(s) open", sessions.len());
+
+        Ok(())
+    }
+
+    /// Sends the client its tunnel settings, if it negotiated config push.
+    async fn push_config(&self, socket: &UdpSocket, session: &mut Session) -> Result<()> {
+        let transport = &mut session.transport;
+        let negotiated = transport.capabilities();
+        if !negotiated.has(Capabilities::CONFIG_PUSH) {
+            return Ok(());
+        }
+
+        let prefix = self.pool.lock().unwrap().prefix();
+        let config = TunnelConfig {
+            address: session.tunnel_ip.and_then(|ip| IpCidr::new(ip, prefix).ok()),
+            mtu: negotiated.mtu,
+            dns: self.dns.clone(),
+            routes: self.routes.clone(),
+        };
+        log::debug!("Pushing {:?} to session {:08x}", config, session.id);
+        let datagram = transport.seal(&Control::Config(config).encode(), session.remote_id)?;
+        socket.send_to(&datagram, session.addr).await?;
         Ok(())
     }
+
+    async fn handle_transport(
+        &self,
+        socket: &UdpSocket,
+        tun: &TunDevice,
+        sessions: &mut SessionTable,
+        addr: SocketAddr,
+        header: &PacketHeader,
+        packet: TransportData,
+    ) -> Result<()> {
+        let id = header.session_id;
+        let Some(session) = sessions.get_mut(id) else {
+            return Err(KScopeError::Protocol(format!("unknown session {:08x}", id)));
+        };
+        // Only a packet that authenticated may move the endpoint.
+        let plain = session.transport.open(header, &packet)?;
+        let now = Instant::now();
+
+        if let Some(old) = session.roam(addr, now) {
+            log::info!("{} (session {:08x}) moved from {} to {}", self.peer_name(session.peer), id, old, addr);
+        }
+
+        if Control::is_control(&plain) {
+            return self.handle_control(socket, session, Control::decode(&plain)?).await;
+        }
+        // A peer may only send from addresses routed to it, so it cannot
+        // pose as another client.
+        let src = tun::packet_source(&plain);
+        let allowed = src.zip(session.peer).is_some_and(|(ip, peer)| {
+            source_allowed(&self.client_networks, &self.allowed_ips.lock().unwrap(), &peer, &ip)
+        });
+        if !allowed {
+            log::debug!("Dropping packet from {:?} not allowed for session {:08x}", src, id);
+            return Ok(());
+        }
+        session.last_active = now;
+        tun.write(&plain)
+    }
+
+    async fn handle_control(&self, socket: &UdpSocket, session: &mut Session, message: Control) -> Result<()> {
+        let transport = &mut session.transport;
+
+        match message {
+            Control::KeepAlive(probe) => {
+                let echo = transport.seal(&Control::KeepAliveEcho(probe).encode(), session.remote_id)?;
+                socket.send_to(&echo, session.addr).await?;
+            }
+            Control::KeepAliveEcho(echo) => {
+                if let Some(rtt) = transport.keepalive_echoed(&echo, Instant::now()) {
+                    let srtt = transport.rtt().smoothed.unwrap_or(rtt);
+                    log::trace!("Session {:08x}: rtt {:?}, smoothed {:?}", session.id, rtt, srtt);
+                }
+            }
+            Control::ConfigRequest => self.push_config(socket, session).await?,
+            Control::Config(_) => log::debug!("Ignoring config from client (session {:08x})", session.id),
+        }
+        Ok(())
+    }
+
+    /// Housekeeping once a second: drops peers that went quiet or idle,
+    /// renews the address leases of the rest, sends keepalives on idle
+    /// sessions, and refreshes the session view.
+    async fn tick(&self, socket: &UdpSocket, sessions: &mut SessionTable) {
+        let now = Instant::now();
+        let dead: Vec<u32> = sessions.iter()
+            .filter(|s| s.transport.is_peer_dead(now))
+            .map(|s| s.id)
+            .collect();
+        for session in dead.into_iter().filter_map(|id| sessions.remove(id)) {
+            self.release(&session);
+            self.stats.evicted_dead.fetch_add(1, Ordering::Relaxed);
+            let timeout = self.config.server.keepalive_timeout;
+            log::info!("{} (session {:08x}) timed out after {}s of silence", self.peer_name(session.peer), session.id, timeout);
+        }
+
+        let timeout = self.config.server.session_timeout;
+        if timeout > 0 {
+            for session in sessions.evict_idle(now, Duration::from_secs(timeout)) {
+                self.release(&session);
+                self.stats.evicted_idle.fetch_add(1, Ordering::Relaxed);
+                log::info!("{} (session {:08x}) evicted after {}s without traffic", self.peer_name(session.peer), session.id, timeout);
+            }
+        }
+
+        {
+            let mut pool = self.pool.lock().unwrap();
+            for peer in sessions.iter().filter_map(|s| s.peer) {
+                pool.renew(&peer);
+            }
+        }
+
+        for session in sessions.iter_mut() {
+            let probe = match session.transport.poll_keepalive(now, session.remote_id) {
+                Ok(Some(probe)) => probe,
+                Ok(None) => continue,
+                Err(e) => {
+                    log::warn!("Could not build keepalive for session {:08x}: {}", session.id, e);
+                    continue;
+                }
+            };
+            if let Err(e) = socket.send_to(&probe, session.addr).await {
+                log::debug!("Could not send keepalive to {}: {}", session.addr, e);
+            }
+        }
+
+        self.view.update(sessions.iter().map(Session::info).collect());
+    }
+
+    /// Reacts to an error notice from a client. `id` is our index, or the
+    /// client's when it echoes a packet we sent. Notices are not
+    /// authenticated, so only the session's current endpoint may close it,
+    /// and only once the client has been silent for `NOTICE_GRACE`.
+    fn handle_error(&self, sessions: &mut SessionTable, addr: SocketAddr, id: u32, notice: ErrorPacket) -> Result<()> {
+        let Some(session) = sessions.iter().find(|s| (s.id == id || s.remote_id == id) && s.addr == addr) else {
+            return Err(KScopeError::Protocol(format!("\"{}\" for unknown session {:08x}", notice, id)));
+        };
+        let (id, name) = (session.id, self.peer_name(session.peer));
+        let silence = session.transport.silence(Instant::now());
+
+        match notice.code {
+            ErrorCode::ShuttingDown | ErrorCode::SessionUnknown if silence < NOTICE_GRACE => {
+                log::debug!("Ignoring \"{}\" for session {:08x}: {} heard from {:?} ago", notice, id, name, silence);
+            }
+            ErrorCode::ShuttingDown | ErrorCode::SessionUnknown => {
+                log::info!("{} (session {:08x}) closed the session: {}", name, id, notice);
+                if let Some(session) = sessions.remove(id) {
+                    self.release(&session);
+                }
+            }
+            ErrorCode::AuthFailed | ErrorCode::ServerFull | ErrorCode::VersionUnsupported => {
+                log::warn!("{} (session {:08x}) reported: {}", name, id, notice);
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops routing a closed session's assigned address to its peer. The
+    /// lease itself stays, for when the peer comes back.
+    fn release(&self, session: &Session) {
+        let (Some(peer), Some(ip)) = (session.peer, session.tunnel_ip) else { return };
+        let host = IpCidr::host(ip);
+        let registered = self.registry.get(&peer).is_some_and(|p| p.allowed_ips.contains(&host));
+        let mut allowed_ips = self.allowed_ips.lock().unwrap();
+        if !registered && allowed_ips.lookup(&ip) == Some(&peer) {
+            allowed_ips.remove(&host);
+        }
+    }
+
+    fn peer_name(&self, peer: Option<[u8; 32]>) -> &str {
+        peer.and_then(|k| self.registry.get(&k)).map_or("?", |p| p.name.as_str())
+    }
+
+    async fn handle_tun_packet(
+        &self,
+        socket: &UdpSocket,
+        sessions: &mut SessionTable,
+        packet: &[u8],
+    ) -> Result<()> {
+        let Some(dst) = tun::packet_destination(packet).filter(|ip| self.client_networks.contains(ip)) else {
+            return Ok(());
+        };
+        let peer = self.allowed_ips.lock().unwrap().lookup(&dst).copied();
+        let Some(session) = peer.and_then(|p| sessions.by_peer(&p)) else {
+            log::trace!("No peer for {}", dst);
+            return Ok(());
+        };
+        let datagram = session.transport.seal(packet, session.remote_id)?;
+        session.last_active = Instant::now();
+        socket.send_to(&datagram, session.addr).await?;
+
+        Ok(())
+    }
+}
+
+
+/// Whether `peer` may send from `src`: a client address whose most
+/// specific allowed IP belongs to that peer.
+fn source_allowed(client_networks: &RoutingTable<()>, allowed_ips: &RoutingTable<[u8; 32]>, peer: &[u8; 32], src: &IpAddr) -> bool {
+    client_networks.contains(src) && allowed_ips.lookup(src) == Some(peer)
+}
+
+/// Answers a packet from `addr` with an error notice. The notice is not
+/// sent if it would be larger than that packet, so spoofed traffic cannot
+/// be amplified through us.
+async fn send_error(socket: &UdpSocket, addr: SocketAddr, session_id: u32, code: ErrorCode, request_len: usize) {
+    let reply = Packet::Error(ErrorPacket::new(code, "")).serialize(session_id);
+    if reply.len() > request_len {
+        return;
+    }
+    if let Err(e) = socket.send_to(&reply, addr).await {
+        log::debug!("Could not send \"{}\" to {}: {}", code, addr, e);
+    }
+}
+
+/// Tells every established peer we are going away.
+async fn notify_shutdown(socket: &UdpSocket, sessions: &SessionTable) {
+    let notice = Packet::Error(ErrorPacket::new(ErrorCode::ShuttingDown, ""));
+    for session in sessions.iter() {
+        if let Err(e) = socket.send_to(&notice.serialize(session.remote_id), session.addr).await {
+            log::debug!("Could not notify {}: {}", session.addr, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_must_map_to_peer() {
+        let (a, b) = ([1u8; 32], [2u8; 32]);
+        let client_networks: RoutingTable<()> = ["10.8.0.0/16", "fd00::/64"].iter()
+            .map(|n| (n.parse().unwrap(), ()))
+            .collect();
+        let allowed_ips: RoutingTable<[u8; 32]> = [("10.8.0.0/24", a), ("10.8.0.9/32", b), ("fd00::9/128", b), ("192.0.2.0/24", a)]
+            .iter()
+            .map(|(n, peer)| (n.parse().unwrap(), *peer))
+            .collect();
+        let allowed = |peer: &[u8; 32], src: &str| source_allowed(&client_networks, &allowed_ips, peer, &src.parse().unwrap());
+
+        assert!(allowed(&a, "10.8.0.5"));
+        assert!(!allowed(&b, "10.8.0.5"));
+        // The /32 inside a's /24 belongs to b alone.
+        assert!(allowed(&b, "10.8.0.9"));
+        assert!(!allowed(&a, "10.8.0.9"));
+        assert!(allowed(&b, "fd00::9"));
+        assert!(!allowed(&a, "fd00::9"));
+        assert!(!allowed(&a, "10.8.1.5"));
+        // Outside the client networks, even an allowed IP is refused.
+        assert!(!allowed(&a, "192.0.2.1"));
+    }
 }
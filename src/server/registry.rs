@@ -0,0 +1,188 @@
+use crate::crypto::keyfile::LoadedKeys;
+use crate::crypto::noise::{PeerAuthorizer, NO_PSK};
+use crate::crypto::secret::SecretKey;
+use crate::net::cidr::IpCidr;
+use crate::net::routing::RoutingTable;
+use crate::{KScopeError, Result};
+use base64::{engine::general_purpose, Engine};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+/// One client the server knows about.
+#[derive(Debug, Clone)]
+pub struct PeerEntry {
+    pub name: String,
+    pub public_key: [u8; 32],
+    pub psk: Option<SecretKey>,
+    pub allowed_ips: Vec<IpCidr>,
+    pub enabled: bool,
+}
+
+/// On-disk form of a peer:
+///
+/// ```toml
+/// [[peer]]
+/// name = "alice-laptop"
+/// public_key = "base64..."
+/// psk = "base64..."          # optional
+/// allowed_ips = ["10.0.0.2/32"]
+/// enabled = true             # optional, defaults to true
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PeerRecord {
+    name: String,
+    public_key: String,
+    #[serde(default)]
+    psk: Option<String>,
+    #[serde(default)]
+    allowed_ips: Vec<IpCidr>,
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct PeersFile {
+    #[serde(default)]
+    peer: Vec<PeerRecord>,
+}
+
+fn default_enabled() -> bool { true }
+
+/// All peers the server accepts, queried by the handshake (key → PSK) and
+/// by routing (tunnel address → peer).
+#[derive(Debug, Default)]
+pub struct PeerRegistry {
+    peers: Vec<PeerEntry>,
+    by_key: HashMap<[u8; 32], usize>,
+}
+
+impl PeerRegistry {
+    /// Loads a peers file, or every `*.toml` file in a peers directory.
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut registry = Self::default();
+
+        if path.is_dir() {
+            let mut files: Vec<_> = fs::read_dir(path)?
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.extension().is_some_and(|ext| ext == "toml"))
+                .collect();
+            files.sort();
+            for file in files {
+                registry.load_file(&file)?;
+            }
+        } else {
+            registry.load_file(path)?;
+        }
+
+        Ok(registry)
+    }
+
+    /// Builds a registry from the `PEER_PUBLIC` lines of a key file, all
+    /// sharing its `PSK`, for servers without a peers file.
+    pub fn from_keys(keys: &LoadedKeys) -> Result<Self> {
+        let mut registry = Self::default();
+        for (i, public) in keys.peers.iter().enumerate() {
+            registry.insert(PeerEntry {
+                name: format!("peer{}", i + 1),
+                public_key: *public,
+                psk: keys.psk.clone(),
+                allowed_ips: Vec::new(),
+                enabled: true,
+            })?;
+        }
+        Ok(registry)
+    }
+
+    /// Parses the text of a peers file; `path` only names it in errors.
+    pub fn from_toml(text: &str, path: &Path) -> Result<Self> {
+        let mut registry = Self::default();
+        registry.add_toml(text, path)?;
+        Ok(registry)
+    }
+
+    fn load_file(&mut self, path: &Path) -> Result<()> {
+        let text = Zeroizing::new(fs::read_to_string(path)?);
+        self.add_toml(&text, path)
+    }
+
+    fn add_toml(&mut self, text: &str, path: &Path) -> Result<()> {
+        let file: PeersFile = toml::from_str(text)
+            .map_err(|e| KScopeError::Config(format!("{}: {}", path.display(), e)))?;
+
+        for record in file.peer {
+            let context = |e: KScopeError| KScopeError::Config(format!("{}: peer {}: {}", path.display(), record.name, e));
+            let public_key = decode_base64_key("public_key", &record.public_key).map_err(context)?;
+            let psk = record.psk.map(Zeroizing::new).as_deref()
+                .map(|p| decode_base64_key("psk", p).map(SecretKey::new))
+                .transpose()
+                .map_err(context)?;
+
+            self.insert(PeerEntry {
+                name: record.name.clone(),
+                public_key,
+                psk,
+                allowed_ips: record.allowed_ips,
+                enabled: record.enabled,
+            }).map_err(context)?;
+        }
+        Ok(())
+    }
+
+    pub fn insert(&mut self, entry: PeerEntry) -> Result<()> {
+        if self.by_key.contains_key(&entry.public_key) {
+            return Err(KScopeError::Config("duplicate public key".into()));
+        }
+        self.by_key.insert(entry.public_key, self.peers.len());
+        self.peers.push(entry);
+        Ok(())
+    }
+
+    pub fn get(&self, public_key: &[u8; 32]) -> Option<&PeerEntry> {
+        self.by_key.get(public_key).map(|&i| &self.peers[i])
+    }
+
+    /// Maps each enabled peer's allowed IPs to its public key. A network
+    /// may belong to one peer only.
+    pub fn allowed_ips(&self) -> Result<RoutingTable<[u8; 32]>> {
+        let mut table = RoutingTable::new();
+        for peer in self.peers.iter().filter(|p| p.enabled) {
+            for network in &peer.allowed_ips {
+                if let Some(other) = table.insert(*network, peer.public_key) {
+                    let other = self.get(&other).map_or("?", |p| p.name.as_str());
+                    return Err(KScopeError::Config(format!("{} is allowed for both {} and {}", network, other, peer.name)));
+                }
+            }
+        }
+        Ok(table)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PeerEntry> {
+        self.peers.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
+
+impl PeerAuthorizer for PeerRegistry {
+    fn psk_for(&self, public: &[u8; 32]) -> Option<SecretKey> {
+        let peer = self.get(public).filter(|p| p.enabled)?;
+        Some(peer.psk.clone().unwrap_or(SecretKey::new(NO_PSK)))
+    }
+}
+
+fn decode_base64_key(field: &str, text: &str) -> Result<[u8; 32]> {
+    let bytes = Zeroizing::new(general_purpose::STANDARD.decode(text.trim())
+        .map_err(|e| KScopeError::Config(format!("{} is not valid base64: {}", field, e)))?);
+    <[u8; 32]>::try_from(bytes.as_slice())
+        .map_err(|_| KScopeError::Config(format!("{} must be 32 bytes, got {}", field, bytes.len())))
+}
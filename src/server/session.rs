@@ -0,0 +1,169 @@
+use crate::protocol::transport::{SecureTransport, SessionInfo};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use zeroize::Zeroize;
+
+/// Minimum time between endpoint changes of one session, so packets
+/// arriving from two addresses at once cannot make the endpoint flap.
+const ROAM_HOLDDOWN: Duration = Duration::from_secs(2);
+
+pub struct Session {
+    /// Our receiver index: peers put it in the header of every packet
+    /// they send on this session.
+    pub id: u32,
+    /// The peer's receiver index, put in the header of packets we send.
+    pub remote_id: u32,
+    pub addr: SocketAddr,
+    pub transport: Box<SecureTransport>,
+    pub tunnel_ip: Option<IpAddr>,
+    /// Static key of the peer, known once its handshake is authorized.
+    pub peer: Option<[u8; 32]>,
+    /// Last tunnelled packet in either direction; keepalives and other
+    /// control messages do not count.
+    pub last_active: Instant,
+    last_roam: Option<Instant>,
+}
+
+impl Session {
+    pub fn new(id: u32, remote_id: u32, addr: SocketAddr, transport: SecureTransport) -> Self {
+        Self {
+            id,
+            remote_id,
+            addr,
+            transport: Box::new(transport),
+            tunnel_ip: None,
+            peer: None,
+            last_active: Instant::now(),
+            last_roam: None,
+        }
+    }
+
+    /// Moves the session to `addr` after a packet from there authenticated,
+    /// unless the endpoint already changed within `ROAM_HOLDDOWN`. Returns
+    /// the previous endpoint if it moved.
+    pub fn roam(&mut self, addr: SocketAddr, now: Instant) -> Option<SocketAddr> {
+        if addr == self.addr {
+            return None;
+        }
+        if self.last_roam.is_some_and(|at| now.duration_since(at) < ROAM_HOLDDOWN) {
+            log::debug!("Not moving session {:08x} to {}: endpoint changed too recently", self.id, addr);
+            return None;
+        }
+        self.last_roam = Some(now);
+        Some(std::mem::replace(&mut self.addr, addr))
+    }
+
+    /// A status snapshot.
+    pub fn info(&self) -> SessionInfo {
+        SessionInfo {
+            id: self.id,
+            peer: self.peer,
+            endpoint: self.addr,
+            tunnel_ip: self.tunnel_ip,
+            rtt: self.transport.rtt(),
+            stats: self.transport.stats(),
+        }
+    }
+}
+
+/// All sessions known to the server, keyed by the receiver index it
+/// handed out during the handshake.
+///
+/// Indices are random, so a packet's `session_id` cannot be guessed to
+/// probe other sessions, and a peer keeps its session when its address
+/// changes. TUN traffic reaches a session through its peer key.
+#[derive(Default)]
+pub struct SessionTable {
+    sessions: HashMap<u32, Session>,
+    peers: HashMap<[u8; 32], u32>,
+    /// Newest initiation timestamp accepted from each peer key.
+    initiations: HashMap<[u8; 32], u64>,
+}
+
+impl SessionTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// A fresh random receiver index not used by any live session.
+    pub fn allocate_id(&self) -> u32 {
+        loop {
+            let id = OsRng.next_u32();
+            if id != 0 && !self.sessions.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Session> {
+        self.sessions.values()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Session> {
+        self.sessions.values_mut()
+    }
+
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut Session> {
+        self.sessions.get_mut(&id)
+    }
+
+    /// Adds `session`, replacing any earlier session for the same peer key.
+    pub fn insert(&mut self, session: Session) {
+        if let Some(peer) = session.peer {
+            if let Some(old) = self.peers.insert(peer, session.id) {
+                self.remove(old);
+            }
+        }
+        self.sessions.insert(session.id, session);
+    }
+
+    /// Closes session `id`, wiping its transport keys, and returns it.
+    pub fn remove(&mut self, id: u32) -> Option<Session> {
+        let mut session = self.sessions.remove(&id)?;
+        if let Some(peer) = session.peer {
+            if self.peers.get(&peer) == Some(&id) {
+                self.peers.remove(&peer);
+            }
+        }
+        session.transport.zeroize();
+        Some(session)
+    }
+
+    /// Records `timestamp` as `peer`'s newest initiation, or returns false
+    /// if it is not newer than one already accepted: a replay, or a copy
+    /// overtaken by a later retransmission.
+    pub fn accept_initiation(&mut self, peer: [u8; 32], timestamp: u64) -> bool {
+        let newest = self.initiations.entry(peer).or_insert(0);
+        if timestamp <= *newest {
+            return false;
+        }
+        *newest = timestamp;
+        true
+    }
+
+    /// Closes sessions with no tunnelled traffic for `timeout` and returns
+    /// them.
+    pub fn evict_idle(&mut self, now: Instant, timeout: Duration) -> Vec<Session> {
+        let idle: Vec<u32> = self.sessions.values()
+            .filter(|s| now.duration_since(s.last_active) >= timeout)
+            .map(|s| s.id)
+            .collect();
+        idle.into_iter().filter_map(|id| self.remove(id)).collect()
+    }
+
+    pub fn by_peer(&mut self, peer: &[u8; 32]) -> Option<&mut Session> {
+        let id = self.peers.get(peer)?;
+        self.sessions.get_mut(id)
+    }
+}
@@ -0,0 +1,32 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Server-wide counters, shared so they can be read while the server runs.
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    /// Handshakes refused because `max_connections` sessions were open.
+    pub rejected_full: AtomicU64,
+    /// Handshakes that did not authenticate.
+    pub rejected_auth: AtomicU64,
+    /// Handshakes with no protocol version in common.
+    pub rejected_version: AtomicU64,
+    /// Sessions closed after `session_timeout` without tunnelled traffic.
+    pub evicted_idle: AtomicU64,
+    /// Sessions closed after `keepalive_timeout` of silence.
+    pub evicted_dead: AtomicU64,
+}
+
+impl fmt::Display for ServerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        write!(
+            f,
+            "rejected {} full, {} unauthenticated, {} version; evicted {} idle, {} dead",
+            get(&self.rejected_full),
+            get(&self.rejected_auth),
+            get(&self.rejected_version),
+            get(&self.evicted_idle),
+            get(&self.evicted_dead),
+        )
+    }
+}
@@ -1,7 +1,9 @@
-use std::net::Ipv4Addr;
-use std::process::Command;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use tun_tap::Iface;
+use crate::net::cidr::IpCidr;
 use crate::{Result, KScopeError};
+use super::{command, run_command};
 
 #[derive(Debug, Clone)]
 pub struct TunConfig {
@@ -11,9 +13,22 @@ pub struct TunConfig {
     pub mtu: u16,
 }
 
+impl TunConfig {
+    /// Builds a config from an `addr/prefix` string such as `10.0.0.1/24`.
+    pub fn from_cidr(name: &str, cidr: &str, mtu: u16) -> Result<Self> {
+        let (ip, prefix) = cidr.split_once('/').unwrap_or((cidr, "32"));
+        let ip = ip.parse()
+            .map_err(|_| KScopeError::Config(format!("invalid tunnel address: {}", cidr)))?;
+        let prefix_len = prefix.parse().ok().filter(|p| *p <= 32)
+            .ok_or_else(|| KScopeError::Config(format!("invalid tunnel prefix: {}", cidr)))?;
+
+        Ok(Self { name: name.to_string(), ip, prefix_len, mtu })
+    }
+}
+
 pub struct TunDevice {
     iface: Iface,
-    mtu: usize,
+    mtu: AtomicUsize,
 }
 
 impl TunDevice {
@@ -23,17 +38,17 @@ impl TunDevice {
 
         let cidr = format!("{}/{}", config.ip, config.prefix_len);
 
-        Command::new("ip")
+        command("ip")
             .args(["addr", "add", &cidr, "dev", &config.name])
             .status()
             .ok();
 
-        Command::new("ip")
+        command("ip")
             .args(["link", "set", "up", "dev", &config.name])
             .status()
             .ok();
 
-        Command::new("ip")
+        command("ip")
             .args(["link", "set", "mtu", &config.mtu.to_string(), "dev", &config.name])
             .status()
             .ok();
@@ -42,19 +57,47 @@ impl TunDevice {
 
         Ok(Self {
             iface,
-            mtu: config.mtu as usize,
+            mtu: AtomicUsize::new(config.mtu as usize),
         })
     }
 
-    pub fn read(&mut self) -> Result<Vec<u8>> {
-        let mut buf = vec![0u8; self.mtu];
+    pub fn name(&self) -> &str {
+        self.iface.name()
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.mtu.load(Ordering::Relaxed)
+    }
+
+    /// Replaces the interface's addresses with `address`.
+    pub fn set_address(&self, address: &IpCidr) -> Result<()> {
+        run_command("ip", &["addr", "flush", "dev", self.name()])?;
+        run_command("ip", &["addr", "add", &address.to_string(), "dev", self.name()])
+    }
+
+    pub fn set_mtu(&self, mtu: u16) -> Result<()> {
+        run_command("ip", &["link", "set", "mtu", &mtu.to_string(), "dev", self.name()])?;
+        self.mtu.store(mtu as usize, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Points name resolution on this link at `servers` (systemd-resolved).
+    pub fn set_dns(&self, servers: &[IpAddr]) -> Result<()> {
+        let servers: Vec<String> = servers.iter().map(IpAddr::to_string).collect();
+        let mut args = vec!["dns", self.name()];
+        args.extend(servers.iter().map(String::as_str));
+        run_command("resolvectl", &args)
+    }
+
+    pub fn read(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; self.mtu()];
         let n = self.iface.recv(&mut buf)
             .map_err(|e| KScopeError::Io(e.to_string()))?;
         buf.truncate(n);
         Ok(buf)
     }
 
-    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+    pub fn write(&self, data: &[u8]) -> Result<()> {
         self.iface.send(data)
             .map_err(|e| KScopeError::Io(e.to_string()))?;
         Ok(())
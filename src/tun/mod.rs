@@ -3,7 +3,14 @@ pub mod device;
 pub mod route;  // Добавляем эту строку
 
 pub use device::{TunDevice, TunConfig};
-pub use route::{add_default_route, add_route};  // И эту
+pub use route::{add_default_route, add_route, delete_route, route_to};  // И эту
+
+use crate::crypto::keyfile::{PASSPHRASE_ENV, PASSPHRASE_FD_ENV};
+use crate::{KScopeError, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::process::Command;
+use std::sync::Arc;
+use tokio::sync::mpsc;
 
 #[derive(Debug)]
 pub struct TunPacket {
@@ -16,3 +23,72 @@ impl TunPacket {
         Self { data, protocol }
     }
 }
+
+/// Source address of a raw IPv4/IPv6 packet, if the header is long enough.
+pub fn packet_source(data: &[u8]) -> Option<IpAddr> {
+    match data.first()? >> 4 {
+        4 if data.len() >= 20 => Some(IpAddr::V4(Ipv4Addr::new(data[12], data[13], data[14], data[15]))),
+        6 if data.len() >= 40 => Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&data[8..24]).ok()?))),
+        _ => None,
+    }
+}
+
+/// Destination address of a raw IPv4/IPv6 packet, if the header is long enough.
+pub fn packet_destination(data: &[u8]) -> Option<IpAddr> {
+    match data.first()? >> 4 {
+        4 if data.len() >= 20 => Some(IpAddr::V4(Ipv4Addr::new(data[16], data[17], data[18], data[19]))),
+        6 if data.len() >= 40 => Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&data[24..40]).ok()?))),
+        _ => None,
+    }
+}
+
+/// Reads packets from `tun` on a dedicated thread and forwards them over a
+/// channel, since the TUN fd is blocking and must not stall the runtime.
+pub fn spawn_reader(tun: Arc<TunDevice>, capacity: usize) -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel(capacity.max(1));
+
+    std::thread::spawn(move || loop {
+        match tun.read() {
+            Ok(packet) => {
+                if tx.blocking_send(packet).is_err() {
+                    break;
+                }
+            }
+            Err(e) => {
+                log::error!("TUN {} read failed: {}", tun.name(), e);
+                break;
+            }
+        }
+    });
+
+    rx
+}
+
+/// Runs a network configuration command, failing if it does not succeed.
+pub(crate) fn run_command(program: &str, args: &[&str]) -> Result<()> {
+    command_output(program, args).map(|_| ())
+}
+
+/// A `Command` for `program` that does not inherit the key passphrase.
+pub(crate) fn command(program: &str) -> Command {
+    let mut command = Command::new(program);
+    command.env_remove(PASSPHRASE_ENV).env_remove(PASSPHRASE_FD_ENV);
+    command
+}
+
+/// Runs `program` and returns its standard output.
+pub(crate) fn command_output(program: &str, args: &[&str]) -> Result<String> {
+    let output = command(program)
+        .args(args)
+        .output()
+        .map_err(|e| KScopeError::Io(format!("{}: {}", program, e)))?;
+    if !output.status.success() {
+        return Err(KScopeError::Io(format!(
+            "{} {}: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
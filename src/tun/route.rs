@@ -1,11 +1,31 @@
+use super::{command_output, run_command};
 use crate::KScopeError;
+use std::net::IpAddr;
 
-pub fn add_default_route(_interface: &str, _via: Option<&str>) -> Result<(), KScopeError> {
-    println!("[MOCK] Would add default route via {}", _interface);
-    Ok(())
+pub fn add_default_route(interface: &str, via: Option<&str>) -> Result<(), KScopeError> {
+    add_route(interface, "default", via)
 }
 
-pub fn add_route(_interface: &str, _destination: &str, _via: Option<&str>) -> Result<(), KScopeError> {
-    println!("[MOCK] Would add route {} via {}", _destination, _interface);
-    Ok(())
+/// Routes `destination` into `interface`, replacing any existing route to it.
+pub fn add_route(interface: &str, destination: &str, via: Option<&str>) -> Result<(), KScopeError> {
+    let mut args = vec!["route", "replace", destination];
+    if let Some(via) = via {
+        args.extend(["via", via]);
+    }
+    args.extend(["dev", interface]);
+    run_command("ip", &args)
+}
+
+pub fn delete_route(destination: &str) -> Result<(), KScopeError> {
+    run_command("ip", &["route", "del", destination])
+}
+
+/// The gateway, if any, and interface the kernel currently uses to reach
+/// `destination`.
+pub fn route_to(destination: IpAddr) -> Result<(Option<String>, String), KScopeError> {
+    let output = command_output("ip", &["route", "get", &destination.to_string()])?;
+    let words: Vec<&str> = output.split_whitespace().collect();
+    let after = |key| words.windows(2).find(|w| w[0] == key).map(|w| w[1].to_string());
+    let dev = after("dev").ok_or_else(|| KScopeError::Io(format!("no route to {}", destination)))?;
+    Ok((after("via"), dev))
 }